@@ -1,3 +1,39 @@
 fn main() {
     println!("cargo:rustc-link-arg=-Tlinkall.x");
+    if std::env::var("CARGO_FEATURE_DEFMT").is_ok() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    // MQTT_TLS=1 wraps the MQTT connection in TLS, see README
+    println!("cargo:rustc-check-cfg=cfg(mqtt_tls)");
+    println!("cargo:rerun-if-env-changed=MQTT_TLS");
+    println!("cargo:rerun-if-env-changed=MQTT_TLS_CA_CERT");
+    // DEVICE_ID prefixes all MQTT topics, e.g. desk-office/command
+    println!("cargo:rerun-if-env-changed=DEVICE_ID");
+    let device_id = std::env::var("DEVICE_ID").unwrap_or_else(|_| "sven".into());
+    println!("cargo:rustc-env=SVEN_DEVICE_ID={}", device_id);
+
+    if std::env::var("MQTT_TLS").is_ok_and(|v| v == "1") {
+        println!("cargo:rustc-cfg=mqtt_tls");
+    }
+
+    // Reported by SvenCommand::GetFirmwareVersion, "unknown" without git or date
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    let git_hash = command_output("git", &["rev-parse", "--short", "HEAD"]);
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    let build_timestamp = command_output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+}
+
+fn command_output(program: &str, args: &[&str]) -> String {
+    std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|stdout| stdout.trim().to_string())
+        .filter(|stdout| !stdout.is_empty())
+        .unwrap_or_else(|| "unknown".into())
 }
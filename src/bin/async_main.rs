@@ -1,29 +1,79 @@
 #![no_std]
 #![no_main]
+#![feature(alloc_error_handler)]
 
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::net::Ipv4Addr;
 use core::str::from_utf8;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use embassy_executor::Spawner;
-use embassy_net::{tcp::TcpSocket, IpAddress, IpEndpoint, Runner, StackResources};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::dns::{self, DnsQueryType};
+use embassy_net::{
+    tcp::{ConnectError, TcpSocket},
+    IpAddress, IpEndpoint, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4,
+};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+#[cfg(mqtt_tls)]
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, TlsConfig, TlsConnection, TlsContext, TlsError, UnsecureProvider,
+};
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
-use esp_hal::gpio::{Input, Output};
+use esp_hal::gpio::{AnyPin, Input, Level, Output};
+use esp_hal::peripherals::TIMG1;
 use esp_hal::rng::Rng;
-use esp_hal::timer::timg::TimerGroup;
+use esp_hal::time::ExtU64;
+use esp_hal::timer::timg::{MwdtStage, TimerGroup, Wdt};
 use esp_wifi::wifi::WifiStaDevice;
 use esp_wifi::{wifi::WifiDevice, EspWifiController};
-use heapless::Vec;
-use log::{debug, error, info, warn};
+use picoserve::response::StatusCode;
+use picoserve::routing::{get, post};
+use rust_mqtt::client::client_config::MqttVersion;
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
 use rust_mqtt::packet::v5::reason_codes::ReasonCode;
 use rust_mqtt::{client::client::MqttClient, utils::rng_generator::CountingRng};
-use serde::Deserialize;
+use serde::Serialize;
 use serde_json_core::from_slice;
 
+use sven_esp32::diagnostics::{self, DiagnosticsReport, HeapReport};
+use sven_esp32::env::{parse_bool, parse_u32, parse_u8, parse_usize};
+use sven_esp32::error::SvenError;
 use sven_esp32::gpio::PulsePin;
-use sven_esp32::sven_state::{SvenPosition, SvenState, SvenStateMsg, SvenTopic};
+use sven_esp32::ha_discovery::{
+    HaNumberConfig, HaSensorConfig, NUMBER_CONFIG_TOPIC, PERCENT_NUMBER_CONFIG_TOPIC,
+    SENSOR_CONFIG_TOPIC,
+};
+use sven_esp32::net_utils::{exponential_backoff_ms, same_subnet};
+use sven_esp32::ntp;
+use sven_esp32::ota::{OtaError, OtaStartMsg, OtaStatusMsg, OtaUpdater};
+use sven_esp32::rate_limit::CommandRateLimit;
+use sven_esp32::status_led::{self, BlinkPattern, StatusLed};
+use sven_esp32::storage::{ConfigStore, NvsKey};
+use sven_esp32::sven_state::{
+    set_auto_toggle, AutoTogglePhase, BatchCommand, BatchCommands, ConnectionStatus,
+    ConnectionStatusMsg, DeskCommand, MovementLogEntry, SequenceEntries, SvenCommand, SvenPosition,
+    SvenPositionsMsg, SvenProgressMsg, SvenState, SvenStateMsg, SvenTopic, PULSE_PROGRESS,
+};
 
 extern crate alloc;
 
+#[macro_use]
+#[path = "../fmt.rs"]
+mod fmt;
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+use fmt::Debug2Format;
+
 macro_rules! mk_static {
     ($t:ty,$val:expr) => {{
         static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
@@ -37,6 +87,208 @@ const SSID: &str = env!("SSID");
 const PASS: &str = env!("PASSWORD");
 
 const MQTT_HOST: &str = env!("MQTT_HOST");
+// Tried after BROKER_ATTEMPTS consecutive failures of MQTT_HOST, alternating
+// between the two until one of them accepts the connection
+const MQTT_HOST_FALLBACK: Option<&str> = non_empty(option_env!("MQTT_HOST_FALLBACK"));
+const BROKER_ATTEMPTS: u8 = 3;
+
+const MQTT_CLIENT_ID_PREFIX: &str = match option_env!("MQTT_CLIENT_ID_PREFIX") {
+    Some(prefix) => prefix,
+    None => "sven-esp32",
+};
+
+// Credentials are only sent when both are set and non-empty
+const MQTT_USERNAME: Option<&str> = non_empty(option_env!("MQTT_USERNAME"));
+const MQTT_PASSWORD: Option<&str> = non_empty(option_env!("MQTT_PASSWORD"));
+
+// MQTT_VERSION=3 for brokers that reject v5 connections, defaults to v5
+const MQTT_VERSION: MqttVersion = match option_env!("MQTT_VERSION") {
+    Some(version) => parse_mqtt_version(version),
+    None => MqttVersion::MQTTv5,
+};
+
+// MQTT_STATE_QOS=1 publishes state updates with QoS1 and retries them on
+// network errors, defaults to QoS0
+const MQTT_STATE_QOS: QualityOfService = match option_env!("MQTT_STATE_QOS") {
+    Some(qos) => match parse_u8(qos) {
+        0 => QualityOfService::QoS0,
+        1 => QualityOfService::QoS1,
+        _ => panic!("MQTT_STATE_QOS must be 0 or 1"),
+    },
+    None => QualityOfService::QoS0,
+};
+const MQTT_STATE_RETRIES: u8 = match MQTT_STATE_QOS {
+    QualityOfService::QoS1 => 3,
+    _ => 0,
+};
+const PUBLISH_RETRY_DELAY_MS: u32 = 200;
+
+// ALLOW_REMOTE_REBOOT=false rejects SvenCommand::Reboot
+const ALLOW_REMOTE_REBOOT: bool = match option_env!("ALLOW_REMOTE_REBOOT") {
+    Some(allow) => parse_bool(allow),
+    None => true,
+};
+// Time for the status message to be delivered before a reboot
+const REBOOT_DELAY_MS: u32 = 500;
+
+// MQTT and HTTP commands executed per second at most, see CommandRateLimit
+const COMMAND_RATE_LIMIT: u8 = match option_env!("COMMAND_RATE_LIMIT") {
+    Some(limit) => parse_u8(limit),
+    None => 2,
+};
+
+// Minimum rest of the motor between two pulses
+const MOTOR_COOLDOWN_MS: u32 = match option_env!("MOTOR_COOLDOWN_MS") {
+    Some(ms) => parse_u32(ms),
+    None => 0,
+};
+
+const NTP_SERVER: &str = match option_env!("NTP_SERVER") {
+    Some(server) => server,
+    None => "pool.ntp.org",
+};
+const NTP_SYNC_INTERVAL_MS: u32 = 60 * 60 * 1000;
+
+// Status LED, must not be one of the desk or button pins
+const LED_GPIO: u8 = match option_env!("LED_GPIO") {
+    Some(gpio) => parse_u8(gpio),
+    None => 2,
+};
+
+// Static IPv4 configuration, DHCP is used when none of these are set
+const STATIC_IP: Option<&str> = option_env!("STATIC_IP");
+const STATIC_GATEWAY: Option<&str> = option_env!("STATIC_GATEWAY");
+const STATIC_SUBNET_PREFIX: Option<&str> = option_env!("STATIC_SUBNET_PREFIX");
+
+// Size of the MQTT send and receive buffers and the largest accepted packet.
+// MQTT_BUFFER_SIZE may only lower it as far as the largest payloads allow.
+const MQTT_BUFFER_SIZE: usize = match option_env!("MQTT_BUFFER_SIZE") {
+    Some(size) => parse_usize(size),
+    None => 1280,
+};
+const HA_DISCOVERY_PAYLOAD_SIZE: usize = 768;
+// Largest OTA chunk, see README
+const OTA_CHUNK_SIZE: usize = 1024;
+// Topic, properties and fixed header of a PUBLISH packet
+const MQTT_PACKET_OVERHEAD: usize = 256;
+const _: () = assert!(
+    MQTT_BUFFER_SIZE >= HA_DISCOVERY_PAYLOAD_SIZE + MQTT_PACKET_OVERHEAD,
+    "MQTT_BUFFER_SIZE is too small for the Home Assistant discovery payloads"
+);
+const _: () = assert!(
+    MQTT_BUFFER_SIZE >= OTA_CHUNK_SIZE + MQTT_PACKET_OVERHEAD,
+    "MQTT_BUFFER_SIZE is too small for OTA chunks"
+);
+
+// MAC based default, NVS or SvenCommand::SetMqttClientId, read on every connect
+static MQTT_CLIENT_ID: BlockingMutex<CriticalSectionRawMutex, RefCell<heapless::String<32>>> =
+    BlockingMutex::new(RefCell::new(heapless::String::new()));
+const MAX_CLIENT_ID_LEN: usize = 23;
+
+// Bounds of the reconnect backoff, adjustable with SvenCommand::SetReconnectDelay
+static BASE_RECONNECT_MS: AtomicU32 = AtomicU32::new(1000);
+static MAX_RECONNECT_MS: AtomicU32 = AtomicU32::new(60_000);
+const BASE_RECONNECT_RANGE_MS: (u32, u32) = (500, 10_000);
+const MAX_RECONNECT_RANGE_MS: (u32, u32) = (1000, 300_000);
+
+// While a button is held, a movement command of this duration is queued every period
+const BUTTON_REPEAT_MS: u32 = 200;
+const BUTTON_DEBOUNCE_MS: u64 = 20;
+
+// The hardware watchdog resets the chip unless the main loop kicks WATCHDOG_KICK
+// within WATCHDOG_TIMEOUT_SECS. While connected to the broker it is fed on a timer
+// instead, see watchdog_task.
+const WATCHDOG_TIMEOUT_SECS: u64 = 30;
+const WATCHDOG_KICK_INTERVAL_MS: u32 = 10_000;
+static WATCHDOG_KICK: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Every step of connecting to the broker is aborted after this long, see connect_step
+const CONNECT_STEP_TIMEOUT_MS: u32 = 10_000;
+
+const DIAGNOSTICS_INTERVAL_MS: u32 = 60 * 60 * 1000;
+const HEAP_MONITOR_INTERVAL_MS: u32 = 60_000;
+const LOW_HEAP_BYTES: u32 = 4096;
+const RSSI_REFRESH_MS: u32 = 30_000;
+const RSSI_WARN_DBM: i8 = -80;
+// The MQTT session is dropped after this many consecutive readings below
+// RSSI_POOR_DBM and only resumed once the signal is back above it
+const RSSI_POOR_DBM: i8 = -85;
+const RSSI_POOR_READINGS: u8 = 2;
+static WIFI_SIGNAL_POOR: AtomicBool = AtomicBool::new(false);
+static POOR_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Desk commands from both MQTT and the physical buttons are executed from this queue
+static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, QueuedCommand, 8> = Channel::new();
+// The executor saves the state once no command arrived for this long, so that held
+// buttons and command bursts do not write the flash after every step
+const NVS_SAVE_DEBOUNCE_MS: u32 = 2000;
+
+// Moves deferred by SvenCommand::Schedule, executed by the scheduler task
+const MAX_SCHEDULED: usize = 4;
+type ScheduledMoves = heapless::Vec<(Instant, SvenPosition), MAX_SCHEDULED>;
+static SCHEDULE: BlockingMutex<CriticalSectionRawMutex, RefCell<ScheduledMoves>> =
+    BlockingMutex::new(RefCell::new(heapless::Vec::new()));
+static SCHEDULE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Standing and sitting intervals of SvenCommand::Remind and StartAutoToggle, None
+// disables the reminders
+static REMINDER: Signal<CriticalSectionRawMutex, Option<(u16, u16)>> = Signal::new();
+// A reminder is published this long before the desk moves
+const REMINDER_LEAD_MS: u32 = 60_000;
+// Position after a command moved the desk, re-syncs the reminder cycle
+static POSITION_CHANGED: Signal<CriticalSectionRawMutex, SvenPosition> = Signal::new();
+
+// Positions and hold times of SvenCommand::MoveSequence, run by the sequence task
+static SEQUENCE: Signal<CriticalSectionRawMutex, SequenceEntries> = Signal::new();
+// Set by SvenCommand::CancelSequence, checked between the steps of a sequence
+static SEQUENCE_CANCELLED: AtomicBool = AtomicBool::new(false);
+// Holds are split into slices of this length to react to cancellation
+const SEQUENCE_POLL_MS: u32 = 1000;
+
+// Batches received on {DEVICE_ID}/command/batch, run by the batch task. A new
+// batch replaces the running one.
+static BATCH: Signal<CriticalSectionRawMutex, BatchCommands> = Signal::new();
+// Set by an EmergencyStop, checked between the commands of a batch
+static BATCH_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+// A FactoryReset is only executed when repeated within this window
+const FACTORY_RESET_CONFIRM_MS: u32 = 10_000;
+static FACTORY_RESET_REQUESTED: BlockingMutex<
+    CriticalSectionRawMutex,
+    core::cell::Cell<Option<Instant>>,
+> = BlockingMutex::new(core::cell::Cell::new(None));
+
+// Messages published by the MQTT loop on behalf of other tasks
+// Large enough for a page of the movement log, see MOVEMENT_LOG_PAGE_SIZE
+const OUTBOX_PAYLOAD_SIZE: usize = 1024;
+const MOVEMENT_LOG_PAGE_SIZE: usize = 6;
+static OUTBOX: Channel<CriticalSectionRawMutex, OutgoingMessage, 4> = Channel::new();
+
+// Connection transitions happen while the broker is unreachable, so they are
+// queued here and published once the MQTT client is connected again
+static CONNECTION_STATUS: Channel<CriticalSectionRawMutex, ConnectionStatusMsg, 8> = Channel::new();
+static LAST_CONNECTION_STATUS: BlockingMutex<
+    CriticalSectionRawMutex,
+    core::cell::Cell<Option<ConnectionStatus>>,
+> = BlockingMutex::new(core::cell::Cell::new(None));
+
+// DHCP, DNS, MQTT, HTTP and NTP sockets, plus one spare
+const STACK_SOCKETS: usize = 6;
+const HTTP_PORT: u16 = 80;
+
+type SharedSvenState = Mutex<NoopRawMutex, SvenState<'static>>;
+type SharedConfigStore = Mutex<NoopRawMutex, ConfigStore>;
+const MQTT_PORT: u16 = if cfg!(mqtt_tls) { 8883 } else { 1883 };
+
+// DER encoded CA certificate of the broker, only needed when built with MQTT_TLS=1
+#[cfg(mqtt_tls)]
+const MQTT_TLS_CA_CERT: &[u8] = include_bytes!(env!(
+    "MQTT_TLS_CA_CERT",
+    "MQTT_TLS=1 requires MQTT_TLS_CA_CERT to point to the broker CA certificate"
+));
+#[cfg(mqtt_tls)]
+const TLS_READ_BUFFER_SIZE: usize = 16640;
+#[cfg(mqtt_tls)]
+const TLS_WRITE_BUFFER_SIZE: usize = 4096;
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
@@ -48,19 +300,25 @@ async fn main(spawner: Spawner) {
     esp_alloc::heap_allocator!(72 * 1024);
 
     let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let wdt = TimerGroup::new(peripherals.TIMG1).wdt;
     let mut rng = Rng::new(peripherals.RNG);
 
     let d2 = peripherals.GPIO5;
     let d3 = peripherals.GPIO7;
     let d7 = peripherals.GPIO9;
     let d8 = peripherals.GPIO10;
+    // SAFETY: LED_GPIO is not used for anything else
+    let status_led = StatusLed::new(unsafe { AnyPin::steal(LED_GPIO) });
 
-    let pin_up = PulsePin::new(Output::new(d2, esp_hal::gpio::Level::Low), true);
-    let pin_down = PulsePin::new(Output::new(d3, esp_hal::gpio::Level::Low), true);
+    let pin_up =
+        PulsePin::active_high(Output::new(d2, Level::Low)).with_cooldown(MOTOR_COOLDOWN_MS);
+    let pin_down =
+        PulsePin::active_high(Output::new(d3, Level::Low)).with_cooldown(MOTOR_COOLDOWN_MS);
 
     let button_up = Input::new(d7, esp_hal::gpio::Pull::Down);
     let button_down = Input::new(d8, esp_hal::gpio::Pull::Down);
 
+    #[cfg(feature = "log")]
     esp_println::logger::init_logger_from_env();
 
     // configure wifi
@@ -75,22 +333,32 @@ async fn main(spawner: Spawner) {
     esp_hal_embassy::init(timg0.timer1);
     info!("Embassy initialized!");
 
-    let mut config = embassy_net::Config::dhcpv4(Default::default());
+    let mut config = network_config();
     config.ipv6 = embassy_net::ConfigV6::None;
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
     let (stack, runner) = embassy_net::new(
         wifi_device,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(
+            StackResources<STACK_SOCKETS>,
+            StackResources::<STACK_SOCKETS>::new()
+        ),
         seed,
     );
 
+    let mut mac = [0u8; 6];
+    esp_wifi::wifi::sta_mac(&mut mac);
+    set_client_id(make_client_id(&mac));
+
+    spawner.spawn(led_task(status_led)).ok();
     spawner.spawn(connection(wifi_controller)).ok();
     spawner.spawn(net_task(runner)).ok();
+    spawner.spawn(button_manager(button_up, button_down)).ok();
 
     info!("Waiting for network to be ready...");
     stack.wait_config_up().await;
+    set_connection_status(ConnectionStatus::WifiConnected);
 
     info!("Waiting to get IP address...");
     if let Some(config) = stack.config_v4() {
@@ -102,10 +370,82 @@ async fn main(spawner: Spawner) {
         error!("No IPv4 configuration available!");
     }
 
-    let mut sven_state = SvenState::new(pin_up, pin_down, button_up, button_down).await;
+    let mut config_store = ConfigStore::new();
+    let mut sven_state = match SvenState::load_from_nvs(&mut config_store) {
+        Some(saved) => {
+            info!(
+                "Restored height_mm {}, position {:?} from NVS",
+                saved.height_mm, saved.position
+            );
+            SvenState::new_uncalibrated(pin_up, pin_down, saved.height_mm, saved.position)
+        }
+        None => {
+            info!("No saved state in NVS, desk will be homed on first move");
+            SvenState::new(pin_up, pin_down).await
+        }
+    };
+    sven_state.load_calibration(&mut config_store);
+    sven_state.load_lock(&mut config_store);
+    sven_state.load_direction_swap(&mut config_store);
+    sven_state.load_speed_factor(&mut config_store);
+    sven_state.load_odometer(&mut config_store);
+    sven_state.load_limits(&mut config_store);
+    sven_state.load_positions(&mut config_store);
+    if let Some(client_id) = config_store.read::<heapless::String<32>>(NvsKey::MqttClientId) {
+        info!("Restored MQTT client id {} from NVS", client_id);
+        set_client_id(client_id);
+    }
+    if let Some((base_ms, max_ms)) = config_store.read::<(u32, u32)>(NvsKey::ReconnectDelay) {
+        let (base_ms, max_ms) = set_reconnect_delay(base_ms, max_ms);
+        info!(
+            "Restored reconnect delay {} ms, max {} ms from NVS",
+            base_ms, max_ms
+        );
+    }
+    let sven_state = &*mk_static!(SharedSvenState, Mutex::new(sven_state));
+    let config_store = &*mk_static!(SharedConfigStore, Mutex::new(config_store));
+    spawner
+        .spawn(command_executor(sven_state, config_store))
+        .ok();
+    spawner.spawn(http_server(stack, sven_state)).ok();
+    spawner.spawn(diagnostics_task()).ok();
+    spawner.spawn(heap_monitor_task()).ok();
+    spawner.spawn(ntp_task(stack)).ok();
+    spawner.spawn(scheduler_task()).ok();
+    spawner.spawn(reminder_task()).ok();
+    spawner.spawn(pulse_progress_task()).ok();
+    spawner.spawn(sequence_task(sven_state, config_store)).ok();
+    spawner.spawn(state_publisher_task(sven_state)).ok();
+    spawner.spawn(batch_task(sven_state, config_store)).ok();
+
+    // TLS record buffers are too large for the task arena, keep them static
+    #[cfg(mqtt_tls)]
+    let tls_read_buffer = mk_static!([u8; TLS_READ_BUFFER_SIZE], [0; TLS_READ_BUFFER_SIZE]);
+    #[cfg(mqtt_tls)]
+    let tls_write_buffer = mk_static!([u8; TLS_WRITE_BUFFER_SIZE], [0; TLS_WRITE_BUFFER_SIZE]);
 
+    spawner.spawn(watchdog_task(wdt)).ok();
+
+    let mut retry_count: u32 = 0;
+    // Attempts in the current primary/fallback cycle, see MQTT_HOST_FALLBACK
+    let mut broker_selector: u8 = 0;
     loop {
-        sven_state.handle_button_press().await;
+        let delay_ms = backoff_delay_ms(retry_count);
+        info!("Connecting in {} ms (attempt {})", delay_ms, retry_count);
+        status_led::set_status(BlinkPattern::Fast);
+        set_connection_status(ConnectionStatus::MqttConnecting);
+        watchdog_sleep(delay_ms).await;
+        while WIFI_SIGNAL_POOR.load(Ordering::Relaxed) {
+            info!("Waiting for the WiFi signal to improve before connecting");
+            watchdog_sleep(RSSI_REFRESH_MS).await;
+        }
+        retry_count = retry_count.saturating_add(1);
+        let mqtt_host = match MQTT_HOST_FALLBACK {
+            Some(fallback) if broker_selector >= BROKER_ATTEMPTS => fallback,
+            _ => MQTT_HOST,
+        };
+        broker_selector = (broker_selector + 1) % (2 * BROKER_ATTEMPTS);
+
         let mut rx_buffer = [0; 4096];
         let mut tx_buffer = [0; 4096];
 
@@ -113,147 +453,239 @@ async fn main(spawner: Spawner) {
 
         socket.set_timeout(Some(embassy_time::Duration::from_secs(3600)));
 
-        let ip = str_to_ip(MQTT_HOST);
-        let port = 1883;
+        let ip = match connect_step(resolve_mqtt_host(mqtt_host, stack)).await {
+            Ok(Ok(ip)) => ip,
+            Ok(Err(e)) => {
+                error!("✗ Failed to resolve MQTT host {}: {:?}", mqtt_host, e);
+                continue;
+            }
+            Err(_) => {
+                error!("✗ Timed out resolving MQTT host {}", mqtt_host);
+                continue;
+            }
+        };
+        let port = MQTT_PORT;
         let remote_endpoint = IpEndpoint::new(ip, port);
         info!("Attempting to connect to {}:{}", ip, port);
-        let connection = socket.connect(remote_endpoint).await;
+        let connection = connect_step(socket.connect(remote_endpoint))
+            .await
+            .unwrap_or(Err(ConnectError::TimedOut));
         warn!("connection: {:?}", connection);
         match connection {
             Ok(()) => {
                 info!("✓ Successfully connected to {}:{}", ip, port);
+
+                #[cfg(not(mqtt_tls))]
+                let transport = socket;
+                #[cfg(mqtt_tls)]
+                let transport = match connect_step(open_tls(
+                    socket,
+                    mqtt_host,
+                    &mut tls_read_buffer[..],
+                    &mut tls_write_buffer[..],
+                    rng.clone(),
+                ))
+                .await
+                {
+                    Ok(Ok(tls)) => {
+                        info!("✓ TLS session established with {}:{}", ip, port);
+                        tls
+                    }
+                    Ok(Err(e)) => {
+                        error!("✗ TLS handshake with {}:{} failed: {:?}", ip, port, e);
+                        continue;
+                    }
+                    Err(_) => {
+                        error!("✗ TLS handshake with {}:{} timed out", ip, port);
+                        continue;
+                    }
+                };
+
+                let client_id = MQTT_CLIENT_ID.lock(|client_id| client_id.borrow().clone());
+                info!("MQTT client id: {}", client_id);
                 let mut config = rust_mqtt::client::client_config::ClientConfig::new(
-                    rust_mqtt::client::client_config::MqttVersion::MQTTv5,
+                    MQTT_VERSION,
                     CountingRng(20000),
                 );
                 config.add_max_subscribe_qos(
                     rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
                 );
-                config.add_client_id("sven-esp32");
-                config.max_packet_size = 100;
+                config.add_client_id(&client_id);
+                if let (Some(username), Some(password)) = (MQTT_USERNAME, MQTT_PASSWORD) {
+                    config.add_username(username);
+                    config.add_password(password);
+                }
+                config.max_packet_size = MQTT_BUFFER_SIZE as u32;
                 config.keep_alive = 3600;
-                let mut recv_buffer = [0; 80];
-                let mut write_buffer = [0; 80];
+                let mut recv_buffer = [0; MQTT_BUFFER_SIZE];
+                let mut write_buffer = [0; MQTT_BUFFER_SIZE];
 
-                config.add_will(SvenTopic::Status.as_str(), b"offline", true);
+                // Last Will Testament, published by the broker if we drop off unexpectedly
+                config.add_will(SvenTopic::Availability.as_str(), b"offline", true);
 
                 let mut client = MqttClient::<_, 5, _>::new(
-                    socket,
+                    transport,
                     &mut write_buffer,
-                    80,
+                    MQTT_BUFFER_SIZE,
                     &mut recv_buffer,
-                    80,
+                    MQTT_BUFFER_SIZE,
                     config,
                 );
 
-                match client.connect_to_broker().await {
+                // A broker that accepts the connection but never answers is
+                // handled like a network error
+                match connect_step(client.connect_to_broker())
+                    .await
+                    .unwrap_or(Err(ReasonCode::NetworkError))
+                {
                     Ok(()) => {
                         info!("✓ Connected to MQTT broker at {}:{}", ip, port);
+                        retry_count = 0;
+                        broker_selector = 0;
+                        status_led::set_status(BlinkPattern::Solid);
+                        set_connection_status(ConnectionStatus::MqttConnected);
+                        POOR_SIGNAL.reset();
+                        // The state is restored from the broker, pending moves may be stale
+                        cancel_schedule();
                     }
+                    // rust-mqtt reports failures as v5 reason codes for both versions,
+                    // v3.1.1 return codes map onto the same NotAuthorized variants
                     Err(mqtt_error) => match mqtt_error {
                         ReasonCode::NetworkError => {
                             error!("MQTT Network Error: {:?}", mqtt_error);
                             continue;
                         }
+                        ReasonCode::NotAuthorized | ReasonCode::BadUserNameOrPassword => {
+                            error!(
+                                "MQTT broker rejected the credentials ({:?}), check MQTT_USERNAME and MQTT_PASSWORD",
+                                mqtt_error
+                            );
+                            continue;
+                        }
                         _ => {
                             error!("Other MQTT Error: {:?}", mqtt_error);
                             continue;
                         }
                     },
                 }
-                // Get Sven State
 
-                client
-                    .subscribe_to_topic(SvenTopic::State.as_str())
-                    .await
-                    .ok();
-                match client.receive_message().await {
-                    Ok((topic, packet)) if topic == SvenTopic::State.as_str() => {
-                        match mqtt_packet_to_sven_state(packet) {
-                            Ok(curr_sven_state) => {
-                                info!(
-                                    "Setting height_mm to {}, position {:?}",
-                                    curr_sven_state.height_mm, curr_sven_state.position
-                                );
-                                sven_state.height_mm = curr_sven_state.height_mm;
-                                sven_state.position = curr_sven_state.position;
-                            }
-                            Err(e) => {
-                                error!("Failed to parse sven state: {:?}", e);
-                            }
-                        }
-                    }
-                    Ok((topic, _)) => {
-                        info!("Received message from mqtt topic {topic}");
-                    }
-                    Err(e) => {
-                        error!("Error receiving sven state: {:?}", e);
-                    }
-                }
-                match client
-                    .unsubscribe_from_topic(SvenTopic::State.as_str())
-                    .await
-                {
-                    Ok(_) => info!("Unsubscribed from topic: {}", SvenTopic::State.as_str()),
-                    Err(e) => error!("Failed to unsubscribe from topic: {:?}", e),
-                }
+                let setup = connect_step(async {
+                    client
+                        .send_message(
+                            SvenTopic::Availability.as_str(),
+                            b"online",
+                            rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+                            true,
+                        )
+                        .await
+                        .unwrap_or_else(|e| {
+                            error!("Failed to publish availability: {:?}", e);
+                        });
 
-                client
-                    .subscribe_to_topic(SvenTopic::Command.as_str())
-                    .await
-                    .ok();
-
-                client
-                    .send_message(
-                        SvenTopic::Status.as_str(),
-                        b"online",
-                        rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
-                        true,
-                    )
-                    .await
-                    .unwrap_or_else(|e| {
-                        error!("Failed to publish SvenStatus: {:?}", e);
-                    });
+                    publish_ha_discovery(&mut client).await;
+                    publish_connection_status(&mut client).await;
+
+                    // The retained state arrives first and replaces the one restored from NVS
+                    client
+                        .subscribe_to_topic(SvenTopic::All.as_str())
+                        .await
+                        .unwrap_or_else(|e| {
+                            error!(
+                                "Failed to subscribe to {}: {:?}",
+                                SvenTopic::All.as_str(),
+                                e
+                            );
+                        });
+                })
+                .await;
+                if setup.is_err() {
+                    error!("✗ Timed out publishing the initial messages, reconnecting");
+                    set_connection_status(ConnectionStatus::MqttDisconnected);
+                    continue;
+                }
+                let mut state_restored = false;
 
+                let mut ota: Option<OtaUpdater> = None;
                 loop {
                     info!("Waiting for incoming MQTT packets...");
-                    match client.receive_message().await {
-                        Ok((topic, packet)) => {
-                            info!("Received packet: {topic}: {:?}", packet);
-                            let text = from_utf8(packet).unwrap_or("");
-                            info!("Received packet text: {}", text);
-                            if let Some(command) = mqtt_packet_to_desk_command(packet).ok() {
-                                info!("Parsed command: {:?}", command);
-                                // Handle the desk command
-                                handle_desk_command(&command, &mut sven_state).await;
-                                // Publish the new sven_state after handling the command
-                                let sven_state_pub = SvenStateMsg::new(&sven_state);
-                                let sven_state_json: serde_json_core::heapless::String<128> =
-                                    serde_json_core::to_string(&sven_state_pub).unwrap_or_else(
-                                        |e| {
-                                            error!("Failed to serialize SvenState: {:?}", e);
-                                            serde_json_core::heapless::String::from("{}")
-                                        },
-                                    );
-                                info!("Publishing SvenState: {:?}", sven_state_pub);
-                                client
-                                    .send_message(SvenTopic::State.as_str(), sven_state_json.as_bytes(), rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0, true)
-                                    .await
-                                    .unwrap_or_else(|e| {
-                                        error!("Failed to publish SvenState: {:?}", e);
-                                    });
-                            } else {
-                                error!("Failed to parse MQTT message");
-                                continue;
+                    match select3(
+                        client.receive_message(),
+                        OUTBOX.receive(),
+                        POOR_SIGNAL.wait(),
+                    )
+                    .await
+                    {
+                        Either3::First(Ok((topic, packet))) => {
+                            match dispatch_mqtt_message(
+                                topic,
+                                packet,
+                                &mut ota,
+                                &mut state_restored,
+                            ) {
+                                Ok(Some(100)) => {
+                                    publish_ota_status(&mut client, 100).await;
+                                    info!("OTA update complete, restarting");
+                                    sleep(1000).await;
+                                    esp_hal::reset::software_reset();
+                                }
+                                Ok(Some(progress)) => {
+                                    publish_ota_status(&mut client, progress).await;
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    publish_error(&mut client, &error_string(&e)).await;
+                                }
                             }
                         }
-                        Err(e) => {
+                        Either3::First(Err(e)) => {
                             error!("Error receiving packet: {:?}", e);
                             break;
                         }
+                        Either3::Second(message) => {
+                            info!(
+                                "Publishing to {}: {}",
+                                message.topic,
+                                message.payload.as_str()
+                            );
+                            // Only state updates confirm that a command was executed
+                            let (qos, retries) = if message.topic == SvenTopic::State.as_str() {
+                                // Ours is newer than a retained state arriving after it
+                                state_restored = true;
+                                (MQTT_STATE_QOS, MQTT_STATE_RETRIES)
+                            } else {
+                                (QualityOfService::QoS0, 0)
+                            };
+                            publish_with_retry(
+                                &mut client,
+                                message.topic,
+                                message.payload.as_bytes(),
+                                qos,
+                                message.retain,
+                                retries,
+                            )
+                            .await
+                            .unwrap_or_else(|e| {
+                                error!("Failed to publish to {}: {:?}", message.topic, e);
+                            });
+                        }
+                        Either3::Third(()) => {
+                            warn!("Disconnecting from MQTT broker until the WiFi signal improves");
+                            client
+                                .send_message(
+                                    SvenTopic::Availability.as_str(),
+                                    b"offline",
+                                    QualityOfService::QoS0,
+                                    true,
+                                )
+                                .await
+                                .ok();
+                            client.disconnect().await.ok();
+                            break;
+                        }
                     }
                     info!("Waiting for next packet...");
                 }
+                set_connection_status(ConnectionStatus::MqttDisconnected);
             }
             Err(e) => {
                 error!("✗ Failed to connect: {:?}", e);
@@ -266,9 +698,10 @@ async fn main(spawner: Spawner) {
 
                     // Check if we're trying to connect to something on our subnet
                     let our_ip = config.address.address().octets();
-                    let target_ip: Vec<&str, 4> = MQTT_HOST.split('.').collect();
-                    let target_ip: Vec<u8, 4> =
-                        target_ip.iter().map(|a| a.parse().unwrap_or(0)).collect();
+                    let target_ip = match ip {
+                        IpAddress::Ipv4(addr) => addr.octets(),
+                        _ => [0; 4],
+                    };
                     let subnet_mask = config.address.prefix_len();
 
                     info!("Network analysis:");
@@ -281,11 +714,11 @@ async fn main(spawner: Spawner) {
                         target_ip[0], target_ip[1], target_ip[2], target_ip[3]
                     );
 
-                    // Simple same-subnet check (assuming /24 network)
-                    if our_ip[0] == target_ip[0]
-                        && our_ip[1] == target_ip[1]
-                        && our_ip[2] == target_ip[2]
-                    {
+                    if same_subnet(
+                        Ipv4Addr::from(our_ip),
+                        Ipv4Addr::from(target_ip),
+                        subnet_mask,
+                    ) {
                         info!("  ✓ Target appears to be on same subnet");
                     } else {
                         info!("  ! Target appears to be on different subnet - routing through gateway");
@@ -308,18 +741,986 @@ async fn main(spawner: Spawner) {
     // for inspiration have a look at the examples at https://github.com/esp-rs/esp-hal/tree/v0.23.1/examples/src/bin
 }
 
+// Retries up to `retries` times if the publish fails with a network error
+async fn publish_with_retry<T, const MAX_PROPERTIES: usize, R>(
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, R>,
+    topic: &str,
+    payload: &[u8],
+    qos: QualityOfService,
+    retain: bool,
+    retries: u8,
+) -> Result<(), ReasonCode>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: rand_core::RngCore,
+{
+    let mut attempt = 0;
+    loop {
+        match client.send_message(topic, payload, qos, retain).await {
+            Err(ReasonCode::NetworkError) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "Publishing to {} failed, retry {} of {}",
+                    topic, attempt, retries
+                );
+                sleep(PUBLISH_RETRY_DELAY_MS).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Announce the desk to Home Assistant, retained so that it survives HA restarts
+async fn publish_ha_discovery<T, const MAX_PROPERTIES: usize, R>(
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, R>,
+) where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: rand_core::RngCore,
+{
+    let number: Result<serde_json_core::heapless::String<HA_DISCOVERY_PAYLOAD_SIZE>, _> =
+        serde_json_core::to_string(&HaNumberConfig::height());
+    let percent: Result<serde_json_core::heapless::String<HA_DISCOVERY_PAYLOAD_SIZE>, _> =
+        serde_json_core::to_string(&HaNumberConfig::height_percent());
+    let sensor: Result<serde_json_core::heapless::String<HA_DISCOVERY_PAYLOAD_SIZE>, _> =
+        serde_json_core::to_string(&HaSensorConfig::height());
+
+    for (topic, payload) in [
+        (NUMBER_CONFIG_TOPIC, number),
+        (PERCENT_NUMBER_CONFIG_TOPIC, percent),
+        (SENSOR_CONFIG_TOPIC, sensor),
+    ] {
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!(
+                    "Failed to serialize discovery payload for {}: {:?}",
+                    topic,
+                    Debug2Format(&e)
+                );
+                continue;
+            }
+        };
+        client
+            .send_message(
+                topic,
+                payload.as_bytes(),
+                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+                true,
+            )
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to publish discovery to {}: {:?}", topic, e);
+            });
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorMsg<'a> {
+    error: &'a str,
+}
+
+#[derive(Serialize)]
+struct RateLimitedMsg {
+    error: &'static str,
+    retry_after_ms: u32,
+}
+
+// Published to SvenTopic::Warning when AbsoluteHeight is outside SetLimits
+#[derive(Serialize)]
+struct ClampedHeightMsg {
+    requested: u32,
+    clamped_to: u32,
+}
+
+#[derive(Serialize)]
+struct WarningMsg<'a> {
+    warning: &'a str,
+}
+
+// A duration move that would pass a limit is skipped and only reported as a warning
+fn warn_on_limit(result: Result<(), SvenError>) -> Result<(), SvenError> {
+    match result {
+        Err(e @ SvenError::LimitReached(_)) => {
+            warn!("Skipping movement: {}", e);
+            publish_json(
+                SvenTopic::Warning.as_str(),
+                &WarningMsg {
+                    warning: &error_string(&e),
+                },
+                false,
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn error_string(e: &SvenError) -> heapless::String<64> {
+    let mut error = heapless::String::new();
+    write!(error, "{}", e).ok();
+    error
+}
+
+async fn publish_error<T, const MAX_PROPERTIES: usize, R>(
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, R>,
+    error: &str,
+) where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: rand_core::RngCore,
+{
+    let payload: serde_json_core::heapless::String<128> =
+        serde_json_core::to_string(&ErrorMsg { error })
+            .unwrap_or_else(|_| serde_json_core::heapless::String::from("{}"));
+    client
+        .send_message(
+            SvenTopic::Error.as_str(),
+            payload.as_bytes(),
+            rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+            false,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to publish error: {:?}", e);
+        });
+}
+
+async fn publish_ota_status<T, const MAX_PROPERTIES: usize, R>(
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, R>,
+    progress: u8,
+) where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: rand_core::RngCore,
+{
+    let payload: serde_json_core::heapless::String<32> =
+        serde_json_core::to_string(&OtaStatusMsg { progress })
+            .unwrap_or_else(|_| serde_json_core::heapless::String::from("{}"));
+    client
+        .send_message(
+            SvenTopic::OtaStatus.as_str(),
+            payload.as_bytes(),
+            rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+            false,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to publish OTA status: {:?}", e);
+        });
+}
+
+// Routes a message received on the {DEVICE_ID}/# subscription. Our own publishes
+// are echoed back by the broker and ignored. Returns the OTA progress to report.
+// Never waits for the SvenState, which is locked while the desk moves.
+fn dispatch_mqtt_message(
+    topic: &str,
+    payload: &[u8],
+    ota: &mut Option<OtaUpdater>,
+    state_restored: &mut bool,
+) -> Result<Option<u8>, SvenError> {
+    if topic == SvenTopic::Command.as_str() || topic == SvenTopic::CommandBinary.as_str() {
+        // Not locking the state here, an EmergencyStop must get through while moving
+        let command = mqtt_packet_to_desk_command(payload)?;
+        if !submit_command(command, CommandSource::Remote) {
+            return Err(SvenError::QueueFull);
+        }
+        Ok(None)
+    } else if topic == SvenTopic::CommandBatch.as_str() {
+        let (batch, _) = from_slice::<BatchCommands>(payload)?;
+        info!("Received a batch of {} commands", batch.len());
+        BATCH.signal(batch);
+        Ok(None)
+    } else if topic == SvenTopic::State.as_str() {
+        // rust-mqtt does not expose the retain flag of received messages. The
+        // broker delivers the retained state right after the SUBACK, before the
+        // echoes of our own publishes, so any state after the first one or after
+        // we published ours (see state_restored in async_main) is an echo.
+        if *state_restored {
+            return Ok(None);
+        }
+        *state_restored = true;
+        let retained = mqtt_packet_to_sven_state(payload)?;
+        info!(
+            "Received retained height_mm {}, position {:?}",
+            retained.height_mm, retained.position
+        );
+        COMMAND_CHANNEL
+            .try_send(QueuedCommand::RestoreState(retained))
+            .map_err(|_| SvenError::QueueFull)?;
+        Ok(None)
+    } else if topic == SvenTopic::OtaControl.as_str() {
+        let started = from_slice::<OtaStartMsg>(payload)
+            .map_err(SvenError::from)
+            .and_then(|(start, _)| OtaUpdater::begin(&start).map_err(SvenError::from));
+        match started {
+            Ok(updater) => {
+                *ota = Some(updater);
+                Ok(Some(0))
+            }
+            Err(e) => {
+                *ota = None;
+                Err(e)
+            }
+        }
+    } else if topic == SvenTopic::OtaData.as_str() {
+        write_ota_chunk(ota, payload)
+    } else {
+        debug!("Ignoring message on {}", topic);
+        Ok(None)
+    }
+}
+
+// Writes a chunk of the OTA image and finishes the update after the last one.
+// Returns the progress in percent whenever it changes.
+fn write_ota_chunk(ota: &mut Option<OtaUpdater>, chunk: &[u8]) -> Result<Option<u8>, SvenError> {
+    let updater = ota.as_mut().ok_or(OtaError::NotStarted)?;
+    let previous = updater.progress();
+    let progress = match updater.write_chunk(chunk) {
+        Ok(progress) => progress,
+        Err(e) => {
+            *ota = None;
+            return Err(e.into());
+        }
+    };
+    if updater.is_complete() {
+        if let Some(updater) = ota.take() {
+            updater.finish()?;
+        }
+    }
+    Ok((progress != previous).then_some(progress))
+}
+
+struct OutgoingMessage {
+    topic: &'static str,
+    payload: serde_json_core::heapless::String<OUTBOX_PAYLOAD_SIZE>,
+    retain: bool,
+}
+
+// Queue a JSON message for the MQTT loop, dropped if the broker is not keeping up
+fn publish_json<T: Serialize>(topic: &'static str, value: &T, retain: bool) {
+    let payload = match serde_json_core::to_string(value) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!(
+                "Failed to serialize message for {}: {:?}",
+                topic,
+                Debug2Format(&e)
+            );
+            return;
+        }
+    };
+    let message = OutgoingMessage {
+        topic,
+        payload,
+        retain,
+    };
+    if OUTBOX.try_send(message).is_err() {
+        warn!("Outbox full, dropping message for {}", topic);
+    }
+}
+
+// Intermediate heights of long movements, dropped like any other outbox message
+// if the broker is not keeping up
+fn publish_progress(height_mm: u32) {
+    publish_json(
+        SvenTopic::Progress.as_str(),
+        &SvenProgressMsg { height_mm },
+        false,
+    );
+}
+
+// Publishes the heights estimated while a pulse is in progress
+#[embassy_executor::task]
+async fn pulse_progress_task() {
+    info!("start pulse progress task");
+    loop {
+        publish_progress(PULSE_PROGRESS.wait().await);
+    }
+}
+
+// Records a lifecycle transition, repeated transitions (e.g. failed reconnect
+// attempts) are only queued once
+fn set_connection_status(status: ConnectionStatus) {
+    if LAST_CONNECTION_STATUS.lock(|last| last.replace(Some(status))) == Some(status) {
+        return;
+    }
+    info!("Connection status: {:?}", status);
+    // Keep the most recent transitions if the broker has been away for long
+    if CONNECTION_STATUS.is_full() {
+        CONNECTION_STATUS.try_receive().ok();
+    }
+    CONNECTION_STATUS
+        .try_send(ConnectionStatusMsg::new(status))
+        .ok();
+}
+
+// Publishes the transitions queued while the broker was unreachable, in order
+async fn publish_connection_status<T, const MAX_PROPERTIES: usize, R>(
+    client: &mut MqttClient<'_, T, MAX_PROPERTIES, R>,
+) where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: rand_core::RngCore,
+{
+    while let Ok(message) = CONNECTION_STATUS.try_receive() {
+        let payload: serde_json_core::heapless::String<64> =
+            match serde_json_core::to_string(&message) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!(
+                        "Failed to serialize connection status: {:?}",
+                        Debug2Format(&e)
+                    );
+                    continue;
+                }
+            };
+        client
+            .send_message(
+                SvenTopic::ConnectionStatus.as_str(),
+                payload.as_bytes(),
+                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+                true,
+            )
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to publish connection status: {:?}", e);
+            });
+    }
+}
+
+// Where a queued command came from, only remote commands are rate limited
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CommandSource {
+    // MQTT and HTTP
+    Remote,
+    // Buttons, scheduled moves, reminders and periodic reports
+    Local,
+}
+
+#[derive(Debug)]
+enum QueuedCommand {
+    Desk {
+        command: DeskCommand,
+        source: CommandSource,
+    },
+    // Retained state received after subscribing, see dispatch_mqtt_message
+    RestoreState(SvenStateMsg),
+}
+
+// Queues a command for the executor, returns false if the queue is full
+fn submit_command(command: DeskCommand, source: CommandSource) -> bool {
+    if let SvenCommand::EmergencyStop = command.command {
+        // Bypass the queue, the executor is busy with the movement
+        warn!("Emergency stop requested");
+        COMMAND_CHANNEL.clear();
+        cancel_schedule();
+        REMINDER.signal(None);
+        SEQUENCE_CANCELLED.store(true, Ordering::Relaxed);
+        BATCH_CANCELLED.store(true, Ordering::Relaxed);
+        PulsePin::abort();
+    } else if let Err(TrySendError::Full(queued)) =
+        COMMAND_CHANNEL.try_send(QueuedCommand::Desk { command, source })
+    {
+        warn!("Command queue full, dropping {:?}", queued);
+        return false;
+    }
+    true
+}
+
+fn schedule_move(position: SvenPosition, delay_ms: u32) -> Result<(), SvenError> {
+    let at = Instant::now() + embassy_time::Duration::from_millis(delay_ms as u64);
+    SCHEDULE
+        .lock(|schedule| schedule.borrow_mut().push((at, position)))
+        .map_err(|_| SvenError::ScheduleFull)?;
+    SCHEDULE_CHANGED.signal(());
+    Ok(())
+}
+
+fn cancel_schedule() {
+    SCHEDULE.lock(|schedule| schedule.borrow_mut().clear());
+    SCHEDULE_CHANGED.signal(());
+}
+
+#[derive(Serialize)]
+struct ReminderMsg {
+    position: SvenPosition,
+    in_s: u32,
+}
+
+// Alternates between Standing and Armrest while reminders are enabled. Moving the
+// desk to the other phase by hand restarts the cycle from there.
+#[embassy_executor::task]
+async fn reminder_task() {
+    info!("start reminder task");
+    let mut intervals = None;
+    // The cycle starts sitting, the first reminder is to stand
+    let mut phase = AutoTogglePhase::Sitting;
+    loop {
+        let Some((stand_after_min, sit_after_min)) = intervals else {
+            set_auto_toggle(None);
+            intervals = REMINDER.wait().await;
+            phase = AutoTogglePhase::Sitting;
+            continue;
+        };
+        let after_min = match phase {
+            AutoTogglePhase::Standing => stand_after_min,
+            AutoTogglePhase::Sitting => sit_after_min,
+        };
+        let ends_at = Instant::now() + embassy_time::Duration::from_secs(after_min as u64 * 60);
+        set_auto_toggle(Some((phase, ends_at)));
+        let resynced = async {
+            loop {
+                let position = POSITION_CHANGED.wait().await;
+                match AutoTogglePhase::from_position(position) {
+                    Some(moved_to) if moved_to != phase => return moved_to,
+                    _ => {}
+                }
+            }
+        };
+        match select3(
+            REMINDER.wait(),
+            resynced,
+            remind(phase.next().position(), ends_at),
+        )
+        .await
+        {
+            Either3::First(changed) => {
+                intervals = changed;
+                phase = AutoTogglePhase::Sitting;
+            }
+            Either3::Second(moved_to) => {
+                info!("Desk moved by hand, re-syncing reminders to {:?}", moved_to);
+                phase = moved_to;
+            }
+            Either3::Third(()) => phase = phase.next(),
+        }
+    }
+}
+
+async fn remind(position: SvenPosition, at: Instant) {
+    let lead = embassy_time::Duration::from_millis(REMINDER_LEAD_MS as u64);
+    embassy_time::Timer::at(at.checked_sub(lead).unwrap_or(Instant::MIN)).await;
+    let lead_ms = at.saturating_duration_since(Instant::now()).as_millis() as u32;
+    info!("Reminder: moving to {:?} in {} ms", position, lead_ms);
+    publish_json(
+        SvenTopic::Reminder.as_str(),
+        &ReminderMsg {
+            position,
+            in_s: lead_ms / 1000,
+        },
+        false,
+    );
+    embassy_time::Timer::at(at).await;
+    submit_command(
+        DeskCommand {
+            command: SvenCommand::Position,
+            value: position as u32,
+            correlation_id: None,
+        },
+        CommandSource::Local,
+    );
+}
+
+#[derive(Serialize)]
+struct SequenceProgressMsg {
+    step: usize,
+    total: usize,
+}
+
+// Runs the sequences of SvenCommand::MoveSequence outside of the command
+// executor, so that other commands are handled during the holds
+#[embassy_executor::task]
+async fn sequence_task(
+    sven_state: &'static SharedSvenState,
+    config_store: &'static SharedConfigStore,
+) {
+    info!("start sequence task");
+    loop {
+        let entries = SEQUENCE.wait().await;
+        run_sequence(&entries, sven_state, config_store).await;
+    }
+}
+
+// A sequence stops when it is cancelled or replaced by a new one
+fn sequence_interrupted() -> bool {
+    SEQUENCE_CANCELLED.load(Ordering::Relaxed) || SEQUENCE.signaled()
+}
+
+async fn run_sequence(
+    entries: &SequenceEntries,
+    sven_state: &SharedSvenState,
+    config_store: &SharedConfigStore,
+) {
+    let total = entries.len();
+    for (i, &(position, hold_ms)) in entries.iter().enumerate() {
+        if sequence_interrupted() {
+            info!("Sequence stopped before step {} of {}", i + 1, total);
+            return;
+        }
+        info!(
+            "Sequence step {} of {}: {:?} for {} ms",
+            i + 1,
+            total,
+            position,
+            hold_ms
+        );
+        publish_json(
+            SvenTopic::SequenceProgress.as_str(),
+            &SequenceProgressMsg { step: i + 1, total },
+            false,
+        );
+
+        let mut sven_state = sven_state.lock().await;
+        let result = if sven_state.is_locked() {
+            Err(SvenError::DeskLocked)
+        } else {
+            sven_state.move_to_position(position).await
+        };
+        sven_state
+            .save_to_nvs(&mut *config_store.lock().await)
+            .unwrap_or_else(|e| {
+                error!("Failed to save SvenState to NVS: {:?}", e);
+            });
+        if sven_state.height_changed_since_last_publish() {
+            publish_json(SvenTopic::State.as_str(), &sven_state.to_msg(), true);
+        }
+        drop(sven_state);
+        if let Err(e) = result {
+            error!("Sequence step {} failed: {}", i + 1, e);
+            publish_json(
+                SvenTopic::Error.as_str(),
+                &ErrorMsg {
+                    error: &error_string(&e),
+                },
+                false,
+            );
+            return;
+        }
+
+        let mut remaining_ms = hold_ms;
+        while remaining_ms > 0 && !sequence_interrupted() {
+            let slice_ms = remaining_ms.min(SEQUENCE_POLL_MS);
+            sleep(slice_ms).await;
+            remaining_ms -= slice_ms;
+        }
+    }
+    info!("Sequence finished");
+}
+
+#[derive(Serialize)]
+struct BatchAckMsg<'a> {
+    completed: usize,
+    total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+// Runs the batches received on {DEVICE_ID}/command/batch outside of the command
+// executor, so that other commands (e.g. EmergencyStop) are handled during delays
+#[embassy_executor::task]
+async fn batch_task(
+    sven_state: &'static SharedSvenState,
+    config_store: &'static SharedConfigStore,
+) {
+    info!("start batch task");
+    let mut rate_limit = CommandRateLimit::new(COMMAND_RATE_LIMIT);
+    loop {
+        let batch = BATCH.wait().await;
+        if let Err(retry_after_ms) = rate_limit.check(Instant::now()) {
+            warn!("Rate limited batch, retry after {} ms", retry_after_ms);
+            publish_rate_limited(retry_after_ms);
+            continue;
+        }
+        BATCH_CANCELLED.store(false, Ordering::Relaxed);
+        let (completed, result) = run_batch(&batch, sven_state, config_store).await;
+        let error = result.err().map(|e| error_string(&e));
+        publish_json(
+            SvenTopic::BatchAck.as_str(),
+            &BatchAckMsg {
+                completed,
+                total: batch.len(),
+                error: error.as_deref(),
+            },
+            false,
+        );
+    }
+}
+
+fn batch_interrupted() -> bool {
+    BATCH_CANCELLED.load(Ordering::Relaxed) || BATCH.signaled()
+}
+
+// Returns the number of elements executed and the error that stopped the batch
+async fn run_batch(
+    batch: &BatchCommands,
+    sven_state: &SharedSvenState,
+    config_store: &SharedConfigStore,
+) -> (usize, Result<(), SvenError>) {
+    for (i, entry) in batch.iter().enumerate() {
+        if batch_interrupted() {
+            info!("Batch stopped before element {} of {}", i + 1, batch.len());
+            return (i, Err(SvenError::MovementAborted));
+        }
+        match entry {
+            BatchCommand::Command(command) => {
+                info!("Batch element {}: {:?}", i + 1, command);
+                let mut sven_state = sven_state.lock().await;
+                let mut config_store = config_store.lock().await;
+                let result = handle_desk_command(command, &mut sven_state, &mut config_store).await;
+                sven_state
+                    .save_to_nvs(&mut config_store)
+                    .unwrap_or_else(|e| {
+                        error!("Failed to save SvenState to NVS: {:?}", e);
+                    });
+                if command.correlation_id.is_some() {
+                    sven_state.mark_dirty();
+                }
+                if sven_state.height_changed_since_last_publish() {
+                    let mut sven_state_pub = sven_state.to_msg();
+                    sven_state_pub.correlation_id = command.correlation_id.clone();
+                    publish_json(SvenTopic::State.as_str(), &sven_state_pub, true);
+                }
+                if let Err(e) = result {
+                    error!("Batch element {} failed: {}", i + 1, e);
+                    return (i, Err(e));
+                }
+            }
+            &BatchCommand::Delay { delay_ms } => {
+                info!("Batch element {}: waiting {} ms", i + 1, delay_ms);
+                let mut remaining_ms = delay_ms;
+                while remaining_ms > 0 && !batch_interrupted() {
+                    let slice_ms = remaining_ms.min(SEQUENCE_POLL_MS);
+                    sleep(slice_ms).await;
+                    remaining_ms -= slice_ms;
+                }
+            }
+        }
+    }
+    info!("Batch finished");
+    (batch.len(), Ok(()))
+}
+
+// Queues scheduled moves for the executor once they are due
+#[embassy_executor::task]
+async fn scheduler_task() {
+    info!("start scheduler task");
+    loop {
+        let next = SCHEDULE.lock(|schedule| schedule.borrow().iter().map(|&(at, _)| at).min());
+        let Some(next) = next else {
+            SCHEDULE_CHANGED.wait().await;
+            continue;
+        };
+        if let Either::First(_) =
+            select(SCHEDULE_CHANGED.wait(), embassy_time::Timer::at(next)).await
+        {
+            continue;
+        }
+
+        let now = Instant::now();
+        let mut due: heapless::Vec<SvenPosition, MAX_SCHEDULED> = heapless::Vec::new();
+        SCHEDULE.lock(|schedule| {
+            schedule.borrow_mut().retain(|&(at, position)| {
+                if at <= now {
+                    due.push(position).ok();
+                    false
+                } else {
+                    true
+                }
+            })
+        });
+        for position in due {
+            info!("Executing scheduled move to {:?}", position);
+            submit_command(
+                DeskCommand {
+                    command: SvenCommand::Position,
+                    value: position as u32,
+                    correlation_id: None,
+                },
+                CommandSource::Local,
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OdometerMsg {
+    total_distance_mm: u32,
+    equivalent_km: f32,
+}
+
+#[derive(Serialize)]
+struct SelfTestMsg {
+    pin_up_ok: bool,
+    pin_down_ok: bool,
+}
+
+#[derive(Serialize)]
+struct CalibrationEntryMsg {
+    ms: u32,
+    mm: u32,
+}
+
+// Response to QueryCalibration, a full table serializes to less than 700 bytes
+#[derive(Serialize)]
+struct CalibrationMsg {
+    entries: heapless::Vec<CalibrationEntryMsg, 16>,
+    is_modified: bool,
+    speed_factor_percent: u32,
+}
+
+impl CalibrationMsg {
+    fn new(sven_state: &SvenState) -> Self {
+        CalibrationMsg {
+            entries: sven_state
+                .calibration()
+                .iter()
+                .map(|&(ms, mm)| CalibrationEntryMsg { ms, mm })
+                .collect(),
+            is_modified: sven_state.calibration_is_modified(),
+            speed_factor_percent: sven_state.speed_factor_percent(),
+        }
+    }
+}
+
+// GIT_HASH and BUILD_TIMESTAMP are set by build.rs
+#[derive(Serialize)]
+struct FirmwareMsg {
+    version: &'static str,
+    git_hash: &'static str,
+    build_ts: &'static str,
+}
+
+// Published when the executor starts a command
+#[derive(Serialize)]
+struct AckMsg<'a> {
+    command: &'a str,
+    estimated_duration_ms: u32,
+}
+
+// 0 for commands that do not move the desk or whose duration is unknown
+fn estimated_duration_ms(command: &DeskCommand, sven_state: &SvenState) -> u32 {
+    match command.command {
+        SvenCommand::UpDuration | SvenCommand::DownDuration => command.value,
+        SvenCommand::UpRelative => {
+            sven_state.time_to_reach_height(sven_state.height_mm().saturating_add(command.value))
+        }
+        SvenCommand::DownRelative => {
+            sven_state.time_to_reach_height(sven_state.height_mm().saturating_sub(command.value))
+        }
+        SvenCommand::AbsoluteHeight => {
+            sven_state.time_to_reach_height(sven_state.clamp_to_limits(command.value))
+        }
+        SvenCommand::Position => match SvenPosition::try_from(command.value) {
+            Ok(position) if position != SvenPosition::Custom => {
+                sven_state.time_to_reach_height(sven_state.get_position_mm(position))
+            }
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+// Executes queued desk commands one at a time so that the MQTT loop keeps receiving
+#[embassy_executor::task]
+async fn command_executor(
+    sven_state: &'static SharedSvenState,
+    config_store: &'static SharedConfigStore,
+) {
+    info!("start command executor task");
+    let mut last_command: heapless::String<32> = heapless::String::new();
+    let mut rate_limit = CommandRateLimit::new(COMMAND_RATE_LIMIT);
+    info!(
+        "Executing at most {} commands per second",
+        rate_limit.max_per_second()
+    );
+    let mut save_pending = false;
+    loop {
+        let queued = if save_pending {
+            match select(COMMAND_CHANNEL.receive(), sleep(NVS_SAVE_DEBOUNCE_MS)).await {
+                Either::First(queued) => queued,
+                Either::Second(()) => {
+                    save_pending = false;
+                    sven_state
+                        .lock()
+                        .await
+                        .save_to_nvs(&mut *config_store.lock().await)
+                        .unwrap_or_else(|e| {
+                            error!("Failed to save SvenState to NVS: {:?}", e);
+                        });
+                    continue;
+                }
+            }
+        } else {
+            COMMAND_CHANNEL.receive().await
+        };
+        let mut sven_state = sven_state.lock().await;
+        let (command, source) = match queued {
+            QueuedCommand::Desk { command, source } => (command, source),
+            QueuedCommand::RestoreState(state) => {
+                // Validated like the state restored from NVS
+                match sven_state.set_known_height(state.height_mm, state.position) {
+                    Ok(()) => {
+                        info!(
+                            "Restored height {} mm, position {:?} from the broker",
+                            state.height_mm, state.position
+                        );
+                        save_pending = true;
+                    }
+                    Err(e) => warn!("Ignoring the retained state: {}", e),
+                }
+                continue;
+            }
+        };
+        if let SvenCommand::GetDiagnostics = command.command {
+            // Read-only, no need to persist or republish the state
+            let report = DiagnosticsReport::new(&sven_state, &last_command);
+            publish_json(SvenTopic::Diagnostics.as_str(), &report, false);
+            continue;
+        }
+        if source == CommandSource::Remote {
+            if let Err(retry_after_ms) = rate_limit.check(Instant::now()) {
+                warn!(
+                    "Rate limited {:?}, retry after {} ms",
+                    command.command, retry_after_ms
+                );
+                publish_rate_limited(retry_after_ms);
+                continue;
+            }
+        }
+        last_command.clear();
+        write!(last_command, "{:?}", command.command).ok();
+        publish_json(
+            SvenTopic::Ack.as_str(),
+            &AckMsg {
+                command: &last_command,
+                estimated_duration_ms: estimated_duration_ms(&command, &sven_state),
+            },
+            false,
+        );
+        let mut config_store = config_store.lock().await;
+
+        status_led::set_status(BlinkPattern::DoublePulse);
+        let position_before = sven_state.position;
+        let result = handle_desk_command(&command, &mut sven_state, &mut config_store).await;
+        status_led::set_status(if result.is_ok() {
+            BlinkPattern::Solid
+        } else {
+            BlinkPattern::SOS
+        });
+        if let Err(e) = result {
+            error!("Failed to handle {:?}: {}", command, e);
+            publish_json(
+                SvenTopic::Error.as_str(),
+                &ErrorMsg {
+                    error: &error_string(&e),
+                },
+                false,
+            );
+        }
+        // save_to_nvs skips the write if neither the height nor the position changed
+        save_pending = true;
+        if sven_state.position != position_before {
+            POSITION_CHANGED.signal(sven_state.position);
+        }
+
+        // Publish the new sven_state after handling the command. Clients waiting
+        // for their correlation_id get a state even if nothing changed.
+        if command.correlation_id.is_some() {
+            sven_state.mark_dirty();
+        }
+        if !sven_state.height_changed_since_last_publish() {
+            debug!("Height unchanged, not publishing SvenState");
+            continue;
+        }
+        let mut sven_state_pub = sven_state.to_msg();
+        sven_state_pub.correlation_id = command.correlation_id.clone();
+        info!("Publishing SvenState: {:?}", sven_state_pub);
+        publish_json(SvenTopic::State.as_str(), &sven_state_pub, true);
+    }
+}
+
+// REST API for controlling the desk without an MQTT broker
+#[embassy_executor::task]
+async fn http_server(stack: Stack<'static>, sven_state: &'static SharedSvenState) {
+    info!("start http server task on port {}", HTTP_PORT);
+    let app =
+        picoserve::Router::new()
+            .route(
+                "/api/state",
+                get(move || async move {
+                    let sven_state = sven_state.lock().await;
+                    picoserve::response::Json(sven_state.to_msg())
+                }),
+            )
+            .route(
+                "/api/command",
+                post(
+                    |picoserve::extract::Json(command): picoserve::extract::Json<
+                        DeskCommand,
+                        0,
+                    >| async move {
+                        info!("Received HTTP command: {:?}", command);
+                        if submit_command(command, CommandSource::Remote) {
+                            (StatusCode::OK, "queued\n")
+                        } else {
+                            (StatusCode::SERVICE_UNAVAILABLE, "command queue full\n")
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/api/positions",
+                get(move || async move {
+                    let sven_state = sven_state.lock().await;
+                    picoserve::response::Json(SvenPositionsMsg::new(&sven_state))
+                }),
+            );
+
+    let config = picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(embassy_time::Duration::from_secs(5)),
+        persistent_start_read_request: Some(embassy_time::Duration::from_secs(1)),
+        read_request: Some(embassy_time::Duration::from_secs(1)),
+        write: Some(embassy_time::Duration::from_secs(1)),
+    });
+    let mut tcp_rx_buffer = [0; 1024];
+    let mut tcp_tx_buffer = [0; 1024];
+    let mut http_buffer = [0; 2048];
+    picoserve::listen_and_serve(
+        0,
+        &app,
+        &config,
+        stack,
+        HTTP_PORT,
+        &mut tcp_rx_buffer,
+        &mut tcp_tx_buffer,
+        &mut http_buffer,
+    )
+    .await
+}
+
 #[embassy_executor::task]
 async fn connection(mut controller: esp_wifi::wifi::WifiController<'static>) {
     info!("start connection task");
-    debug!("Device capabilities: {:?}", controller.capabilities());
+    let mut poor_readings: u8 = 0;
+    debug!(
+        "Device capabilities: {:?}",
+        Debug2Format(&controller.capabilities())
+    );
     loop {
         match esp_wifi::wifi::wifi_state() {
             esp_wifi::wifi::WifiState::StaConnected => {
-                // wait until we're no longer connected
-                controller
-                    .wait_for_event(esp_wifi::wifi::WifiEvent::StaDisconnected)
-                    .await;
-                sleep(5000).await
+                // wait until we're no longer connected, refreshing the RSSI meanwhile
+                match select(
+                    controller.wait_for_event(esp_wifi::wifi::WifiEvent::StaDisconnected),
+                    sleep(RSSI_REFRESH_MS),
+                )
+                .await
+                {
+                    Either::First(_) => sleep(5000).await,
+                    Either::Second(_) => {
+                        if let Some(rssi_dbm) = refresh_wifi_rssi(&mut controller).await {
+                            monitor_wifi_rssi(rssi_dbm, &mut poor_readings);
+                        }
+                        continue;
+                    }
+                }
             }
             _ => {}
         }
@@ -336,119 +1737,688 @@ async fn connection(mut controller: esp_wifi::wifi::WifiController<'static>) {
             info!("Wifi started!");
         }
         info!("About to connect...");
+        status_led::set_status(BlinkPattern::Slow);
+        set_connection_status(ConnectionStatus::WifiConnecting);
 
         match controller.connect_async().await {
-            Ok(_) => info!("Wifi connected!"),
+            Ok(_) => {
+                info!("Wifi connected!");
+                set_connection_status(ConnectionStatus::WifiConnected);
+            }
             Err(e) => {
-                error!("Failed to connect to wifi: {e:?}");
+                error!("Failed to connect to wifi: {:?}", e);
                 sleep(5000).await
             }
         }
     }
 }
 
+// There is no RSSI getter for the current connection, scan for our own AP instead
+async fn refresh_wifi_rssi(controller: &mut esp_wifi::wifi::WifiController<'static>) -> Option<i8> {
+    let config = esp_wifi::wifi::ScanConfig {
+        ssid: Some(SSID),
+        ..Default::default()
+    };
+    match controller.scan_with_config_async::<1>(config).await {
+        Ok((access_points, _)) => {
+            let rssi_dbm = access_points.first()?.signal_strength;
+            diagnostics::set_wifi_rssi(rssi_dbm);
+            Some(rssi_dbm)
+        }
+        Err(e) => {
+            warn!("Failed to scan for RSSI: {:?}", e);
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WifiRssiMsg {
+    rssi_dbm: i8,
+}
+
+// Packet loss on a weak signal corrupts MQTT packets, so rather than keep a
+// flaky session the MQTT loop is told to disconnect
+fn monitor_wifi_rssi(rssi_dbm: i8, poor_readings: &mut u8) {
+    publish_json(
+        SvenTopic::WifiRssi.as_str(),
+        &WifiRssiMsg { rssi_dbm },
+        false,
+    );
+    if rssi_dbm < RSSI_WARN_DBM {
+        warn!("Weak WiFi signal: {} dBm", rssi_dbm);
+    }
+    if rssi_dbm >= RSSI_POOR_DBM {
+        *poor_readings = 0;
+        WIFI_SIGNAL_POOR.store(false, Ordering::Relaxed);
+        return;
+    }
+    *poor_readings = poor_readings.saturating_add(1);
+    if *poor_readings >= RSSI_POOR_READINGS && !WIFI_SIGNAL_POOR.swap(true, Ordering::Relaxed) {
+        let mqtt_connected = LAST_CONNECTION_STATUS
+            .lock(|status| status.get() == Some(ConnectionStatus::MqttConnected));
+        if mqtt_connected {
+            POOR_SIGNAL.signal(());
+        }
+    }
+}
+
+// Keeps the wall clock in sync, timestamps stay 0 until the first sync succeeds
+#[embassy_executor::task]
+async fn ntp_task(stack: Stack<'static>) {
+    info!("start ntp task");
+    loop {
+        match ntp::sync(stack, NTP_SERVER).await {
+            Ok(epoch_s) => info!("Synchronized time with {}: {}", NTP_SERVER, epoch_s),
+            Err(e) => warn!("Failed to synchronize time with {}: {:?}", NTP_SERVER, e),
+        }
+        sleep(NTP_SYNC_INTERVAL_MS).await;
+    }
+}
+
+// Republishes the state every publish_interval_ms so that Home Assistant sensors stay fresh.
+// Publishing our state discards the retained one arriving later (see
+// dispatch_mqtt_message), so nothing is published before the broker is connected
+// and the height is known.
+#[embassy_executor::task]
+async fn state_publisher_task(sven_state: &'static SharedSvenState) {
+    info!("start state publisher task");
+    let mut interval_ms = SvenState::MIN_PUBLISH_INTERVAL_MS;
+    loop {
+        sleep(interval_ms).await;
+        let mqtt_connected = LAST_CONNECTION_STATUS
+            .lock(|status| status.get() == Some(ConnectionStatus::MqttConnected));
+        interval_ms = match sven_state.try_lock() {
+            Ok(_) if !mqtt_connected => SvenState::MIN_PUBLISH_INTERVAL_MS,
+            // 0 until restored from NVS or the broker, or homed by a move
+            Ok(sven_state) if sven_state.height_mm() == 0 => {
+                debug!("Height unknown, waiting for the retained state");
+                SvenState::MIN_PUBLISH_INTERVAL_MS
+            }
+            Ok(sven_state) => {
+                publish_json(SvenTopic::State.as_str(), &sven_state.to_msg(), true);
+                sven_state.publish_interval_ms()
+            }
+            // The executor publishes the state once the command is done
+            Err(_) => {
+                debug!("SvenState busy, skipping periodic publish");
+                SvenState::MIN_PUBLISH_INTERVAL_MS
+            }
+        };
+    }
+}
+
+// Queues a diagnostics report every DIAGNOSTICS_INTERVAL_MS
+#[embassy_executor::task]
+async fn diagnostics_task() {
+    info!("start diagnostics task");
+    loop {
+        sleep(DIAGNOSTICS_INTERVAL_MS).await;
+        let command = QueuedCommand::Desk {
+            command: DeskCommand {
+                command: SvenCommand::GetDiagnostics,
+                value: 0,
+                correlation_id: None,
+            },
+            source: CommandSource::Local,
+        };
+        if COMMAND_CHANNEL.try_send(command).is_err() {
+            warn!("Command queue full, skipping diagnostics report");
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn heap_monitor_task() {
+    info!("start heap monitor task");
+    loop {
+        let report = HeapReport::new();
+        publish_json(SvenTopic::Heap.as_str(), &report, false);
+        if report.free_bytes < LOW_HEAP_BYTES {
+            error!("Low heap: {} bytes free", report.free_bytes);
+            publish_json(
+                SvenTopic::Warning.as_str(),
+                &WarningMsg {
+                    warning: "low heap",
+                },
+                false,
+            );
+        }
+        sleep(HEAP_MONITOR_INTERVAL_MS).await;
+    }
+}
+
+// Log the failed allocation before resetting, the heap is too small to recover
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    error!(
+        "Failed to allocate {} bytes with {} bytes free, resetting",
+        layout.size(),
+        esp_alloc::HEAP.free()
+    );
+    esp_hal::reset::software_reset();
+    #[allow(clippy::empty_loop, unreachable_code)]
+    loop {}
+}
+
+#[embassy_executor::task]
+async fn led_task(mut led: StatusLed<'static>) {
+    info!("start led task on GPIO{}", LED_GPIO);
+    let Some(mut status) = status_led::status_receiver() else {
+        error!("No status receiver left for the LED");
+        return;
+    };
+    let mut pattern = BlinkPattern::Slow;
+    loop {
+        if let Either::First(new_pattern) = select(status.changed(), led.blink(pattern)).await {
+            pattern = new_pattern;
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn button_manager(mut button_up: Input<'static>, mut button_down: Input<'static>) {
+    info!("start button manager task");
+    loop {
+        select(button_up.wait_for_high(), button_down.wait_for_high()).await;
+        sleep(BUTTON_DEBOUNCE_MS as u32).await;
+
+        let up = button_up.is_high();
+        if !up && !button_down.is_high() {
+            continue; // bounce
+        }
+
+        while (up && button_up.is_high()) || (!up && button_down.is_high()) {
+            let command = QueuedCommand::Desk {
+                command: DeskCommand {
+                    command: if up {
+                        SvenCommand::UpDuration
+                    } else {
+                        SvenCommand::DownDuration
+                    },
+                    value: BUTTON_REPEAT_MS,
+                    correlation_id: None,
+                },
+                // Held buttons repeat faster than COMMAND_RATE_LIMIT
+                source: CommandSource::Local,
+            };
+            if let Err(TrySendError::Full(queued)) = COMMAND_CHANNEL.try_send(command) {
+                debug!("Command queue full, dropping {:?}", queued);
+            }
+            sleep(BUTTON_REPEAT_MS).await;
+        }
+    }
+}
+
+// Feeds the hardware watchdog while the main loop keeps kicking WATCHDOG_KICK. If the
+// main loop hangs, or a task blocks the executor, the chip is reset.
+//
+// Once connected the main loop waits in receive_message, which must not be
+// cancelled by a timer since rust-mqtt would lose the part of a packet read so
+// far. The watchdog is fed without kicks then, a silent session is still ended
+// by the socket timeout.
+#[embassy_executor::task]
+async fn watchdog_task(mut wdt: Wdt<TIMG1>) {
+    info!("start watchdog task");
+    wdt.set_timeout(MwdtStage::Stage0, WATCHDOG_TIMEOUT_SECS.secs());
+    wdt.enable();
+    loop {
+        let mqtt_connected = LAST_CONNECTION_STATUS
+            .lock(|status| status.get() == Some(ConnectionStatus::MqttConnected));
+        if !mqtt_connected {
+            WATCHDOG_KICK.wait().await;
+        }
+        wdt.feed();
+        sleep(WATCHDOG_KICK_INTERVAL_MS).await;
+    }
+}
+
 #[embassy_executor::task]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static, esp_wifi::wifi::WifiStaDevice>>) {
     runner.run().await
 }
 
+// Exponential backoff between reconnection attempts, starting at BASE_RECONNECT_MS
+fn backoff_delay_ms(attempt: u32) -> u32 {
+    exponential_backoff_ms(
+        attempt,
+        BASE_RECONNECT_MS.load(Ordering::Relaxed),
+        MAX_RECONNECT_MS.load(Ordering::Relaxed),
+    )
+}
+
+// Clamps both delays to their ranges, the maximum is never below the base.
+// Returns the values in effect.
+fn set_reconnect_delay(base_ms: u32, max_ms: u32) -> (u32, u32) {
+    let base_ms = base_ms.clamp(BASE_RECONNECT_RANGE_MS.0, BASE_RECONNECT_RANGE_MS.1);
+    let max_ms = max_ms
+        .clamp(MAX_RECONNECT_RANGE_MS.0, MAX_RECONNECT_RANGE_MS.1)
+        .max(base_ms);
+    BASE_RECONNECT_MS.store(base_ms, Ordering::Relaxed);
+    MAX_RECONNECT_MS.store(max_ms, Ordering::Relaxed);
+    (base_ms, max_ms)
+}
+
 pub async fn sleep(millis: u32) {
     embassy_time::Timer::after(embassy_time::Duration::from_millis(millis as u64)).await;
 }
 
-fn str_to_ip(ip: &str) -> IpAddress {
-    let split_ip: Vec<&str, 4> = ip.split('.').collect();
-    IpAddress::v4(
-        split_ip[0].parse().unwrap_or(0),
-        split_ip[1].parse().unwrap_or(0),
-        split_ip[2].parse().unwrap_or(0),
-        split_ip[3].parse().unwrap_or(0),
+// Kicks the watchdog and bounds a step of connecting to the broker well below
+// WATCHDOG_TIMEOUT_SECS, so that an unreachable or slow broker ends in the
+// reconnect backoff instead of a watchdog reset
+async fn connect_step<F: core::future::Future>(
+    step: F,
+) -> Result<F::Output, embassy_time::TimeoutError> {
+    WATCHDOG_KICK.signal(());
+    embassy_time::with_timeout(
+        embassy_time::Duration::from_millis(CONNECT_STEP_TIMEOUT_MS as u64),
+        step,
+    )
+    .await
+}
+
+// Sleeps while kicking the watchdog, so that long reconnect backoffs do not reset the chip
+async fn watchdog_sleep(millis: u32) {
+    let mut remaining = millis;
+    while remaining > 0 {
+        WATCHDOG_KICK.signal(());
+        let step = remaining.min(WATCHDOG_KICK_INTERVAL_MS);
+        sleep(step).await;
+        remaining -= step;
+    }
+}
+
+#[cfg(mqtt_tls)]
+async fn open_tls<'a>(
+    socket: TcpSocket<'a>,
+    host: &'a str,
+    read_buffer: &'a mut [u8],
+    write_buffer: &'a mut [u8],
+    rng: Rng,
+) -> Result<TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>, TlsError> {
+    let config = TlsConfig::new()
+        .with_server_name(host)
+        .with_ca(Certificate::X509(MQTT_TLS_CA_CERT));
+    let mut tls = TlsConnection::new(socket, read_buffer, write_buffer);
+    tls.open(TlsContext::new(
+        &config,
+        UnsecureProvider::new::<Aes128GcmSha256>(TlsRng(rng)),
+    ))
+    .await?;
+    Ok(tls)
+}
+
+// The hardware RNG produces true random numbers while the radio is enabled,
+// which is always the case once wifi is up.
+#[cfg(mqtt_tls)]
+struct TlsRng(Rng);
+
+#[cfg(mqtt_tls)]
+impl rand_core::RngCore for TlsRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.0.random() as u64) << 32 | self.0.random() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.read(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(mqtt_tls)]
+impl rand_core::CryptoRng for TlsRng {}
+
+const fn parse_mqtt_version(value: &str) -> MqttVersion {
+    match value.as_bytes() {
+        b"3" | b"3.1.1" => MqttVersion::MQTTv3,
+        b"5" => MqttVersion::MQTTv5,
+        _ => panic!("MQTT_VERSION must be 3 or 5"),
+    }
+}
+
+const fn non_empty(value: Option<&'static str>) -> Option<&'static str> {
+    match value {
+        Some(value) if !value.is_empty() => Some(value),
+        _ => None,
+    }
+}
+
+// Letters, digits and hyphens only, and no longer than the 23 characters every
+// broker has to accept
+fn is_valid_client_id(id: &str) -> bool {
+    (1..=MAX_CLIENT_ID_LEN).contains(&id.len())
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+fn set_client_id(id: heapless::String<32>) {
+    MQTT_CLIENT_ID.lock(|client_id| *client_id.borrow_mut() = id);
+}
+
+// Unique per device so that several desks can share a broker, e.g. sven-esp32-AABBCCDDEEFF
+fn make_client_id(mac: &[u8; 6]) -> heapless::String<32> {
+    let mut client_id = heapless::String::new();
+    write!(
+        client_id,
+        "{}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        MQTT_CLIENT_ID_PREFIX, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
     )
+    .unwrap_or_else(|_| warn!("MQTT client id truncated to {}", client_id));
+    client_id
+}
+
+fn network_config() -> embassy_net::Config {
+    match (STATIC_IP, STATIC_GATEWAY, STATIC_SUBNET_PREFIX) {
+        (None, None, None) => embassy_net::Config::dhcpv4(Default::default()),
+        (Some(ip), Some(gateway), Some(prefix)) => {
+            let Some(IpAddress::Ipv4(address)) = str_to_ip(ip) else {
+                panic!("STATIC_IP {:?} is not a valid IPv4 address", ip);
+            };
+            let Some(IpAddress::Ipv4(gateway)) = str_to_ip(gateway) else {
+                panic!("STATIC_GATEWAY {:?} is not a valid IPv4 address", gateway);
+            };
+            let prefix = match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= 32 => prefix,
+                _ => panic!("STATIC_SUBNET_PREFIX {:?} must be between 0 and 32", prefix),
+            };
+            info!("Using static IP {}/{} via {}", address, prefix, gateway);
+
+            // Without DHCP there is no DNS server, assume the gateway provides one
+            let mut dns_servers = heapless::Vec::new();
+            dns_servers.push(gateway).ok();
+            embassy_net::Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(address, prefix),
+                gateway: Some(gateway),
+                dns_servers,
+            })
+        }
+        _ => panic!(
+            "STATIC_IP, STATIC_GATEWAY and STATIC_SUBNET_PREFIX must either all be set or all be unset"
+        ),
+    }
 }
 
-#[derive(Deserialize, Debug)]
-pub enum SvenCommand {
-    UpDuration,     // value: ms
-    DownDuration,   // value: ms
-    UpRelative,     // value: mm
-    DownRelative,   // value: mm
-    AbsoluteHeight, // value: mm
-    Position,       // value: SvenPosition
-    Calibrate,      // value: SvenPosition
+// Parses a dotted-decimal IPv4 address
+fn str_to_ip(ip: &str) -> Option<IpAddress> {
+    ip.parse::<Ipv4Addr>().ok().map(IpAddress::Ipv4)
 }
 
-#[derive(Deserialize, Debug)]
-pub struct DeskCommand {
-    pub command: SvenCommand,
-    pub value: u32,
+// Accepts either a dotted-decimal IPv4 address or a hostname resolved through DNS
+async fn resolve_mqtt_host(host: &str, stack: Stack<'_>) -> Result<IpAddress, dns::Error> {
+    if let Some(ip) = str_to_ip(host) {
+        return Ok(ip);
+    }
+
+    info!("Resolving {} via DNS", host);
+    stack
+        .dns_query(host, DnsQueryType::A)
+        .await?
+        .first()
+        .copied()
+        .ok_or(dns::Error::Failed)
 }
 
-fn mqtt_packet_to_sven_state(data: &[u8]) -> Result<SvenStateMsg, serde_json_core::de::Error> {
+fn mqtt_packet_to_sven_state(data: &[u8]) -> Result<SvenStateMsg, SvenError> {
     match from_slice::<SvenStateMsg>(data) {
         Ok((sven_state, _)) => {
             info!("Received SvenState: {:?}", sven_state);
             Ok(sven_state)
         }
         Err(e) => {
-            panic!("Failed to parse message to SvenState: {:?}", e);
+            error!(
+                "Failed to parse message to SvenState: {:?}",
+                Debug2Format(&e)
+            );
+            log_payload(data);
+            Err(e.into())
         }
     }
 }
 
-fn mqtt_packet_to_desk_command(data: &[u8]) -> Result<DeskCommand, serde_json_core::de::Error> {
+// Malformed messages are reported and skipped, the payload helps to find the sender
+fn log_payload(data: &[u8]) {
+    match from_utf8(data) {
+        Ok(payload) => error!("Payload: {}", payload),
+        Err(_) => error!("Payload: {} bytes, not UTF-8", data.len()),
+    }
+}
+
+// Commands are JSON on {DEVICE_ID}/command and postcard on {DEVICE_ID}/command/binary,
+// both formats are accepted on either topic
+fn mqtt_packet_to_desk_command(data: &[u8]) -> Result<DeskCommand, SvenError> {
     match from_slice::<DeskCommand>(data) {
         Ok((command, _)) => {
-            info!("Received command: {:?}", command);
+            info!("Received JSON command: {:?}", command);
             Ok(command)
         }
-        Err(e) => {
-            panic!("Failed to parse message: {:?}", e);
-        }
+        Err(e) => match SvenCommand::try_from_postcard(data) {
+            Ok(command) => {
+                info!("Received postcard command: {:?}", command);
+                Ok(command)
+            }
+            Err(_) => {
+                error!("Failed to parse message: {:?}", Debug2Format(&e));
+                log_payload(data);
+                Err(e.into())
+            }
+        },
     }
 }
 
-async fn handle_desk_command<'d>(command: &DeskCommand, sven_state: &mut SvenState<'d>) {
+fn publish_rate_limited(retry_after_ms: u32) {
+    diagnostics::record_rate_limited();
+    publish_json(
+        SvenTopic::Error.as_str(),
+        &RateLimitedMsg {
+            error: "rate_limited",
+            retry_after_ms,
+        },
+        false,
+    );
+}
+
+// Commands that need the firmware (scheduling, reminders, publishing, ...) are
+// handled here, everything else is dispatched by SvenState::handle_command
+async fn handle_desk_command<'d>(
+    command: &DeskCommand,
+    sven_state: &mut SvenState<'d>,
+    config_store: &mut ConfigStore,
+) -> Result<(), SvenError> {
     match command.command {
-        SvenCommand::UpDuration => {
-            info!("Moving up for {} ms", command.value);
-            sven_state.move_up(command.value).await;
-        }
-        SvenCommand::DownDuration => {
-            info!("Moving down for {} ms", command.value);
-            sven_state.move_down(command.value).await;
+        SvenCommand::MoveSequence { ref entries } => {
+            if sven_state.is_locked() {
+                return Err(SvenError::DeskLocked);
+            }
+            info!("Starting a sequence of {} steps", entries.len());
+            SEQUENCE_CANCELLED.store(false, Ordering::Relaxed);
+            SEQUENCE.signal(entries.clone());
         }
-        SvenCommand::UpRelative => {
-            info!("Moving up by {} mm", command.value);
-            sven_state.move_up_relative(command.value).await;
+        SvenCommand::SetMqttClientId { ref id } => {
+            if !is_valid_client_id(id) {
+                return Err(SvenError::InvalidClientId);
+            }
+            info!("MQTT client id set to {}, used from the next reconnect", id);
+            config_store.write(NvsKey::MqttClientId, id)?;
+            set_client_id(id.clone());
         }
-        SvenCommand::DownRelative => {
-            info!("Moving down by {} mm", command.value);
-            sven_state.move_down_relative(command.value).await;
+        SvenCommand::SetReconnectDelay { base_ms, max_ms } => {
+            let (base_ms, max_ms) = set_reconnect_delay(base_ms, max_ms);
+            info!("Reconnect delay set to {} ms, max {} ms", base_ms, max_ms);
+            config_store.write(NvsKey::ReconnectDelay, &(base_ms, max_ms))?;
         }
         SvenCommand::AbsoluteHeight => {
-            info!("Setting absolute height to {} mm", command.value);
-            sven_state.move_to_height(command.value).await;
-        }
-        SvenCommand::Position => {
-            info!("Setting position to {:?}", command.value);
-            let sven_position =
-                SvenPosition::try_from(command.value).unwrap_or(SvenPosition::Armrest);
-            sven_state.move_to_position(sven_position).await;
-        }
-        SvenCommand::Calibrate => {
-            info!("Calibrating position at {:?}", command.value);
-            let bottom_mm = sven_state.get_position_mm(SvenPosition::Bottom);
-            let top_mm = sven_state.get_position_mm(SvenPosition::Top);
-
-            if sven_state.height_mm - bottom_mm < top_mm - sven_state.height_mm {
-                sven_state.move_to_position(SvenPosition::Bottom).await;
+            warn_on_limit(
+                sven_state
+                    .handle_command(command, config_store, publish_progress)
+                    .await,
+            )?;
+            let clamped_to = sven_state.clamp_to_limits(command.value);
+            if clamped_to != command.value {
+                publish_json(
+                    SvenTopic::Warning.as_str(),
+                    &ClampedHeightMsg {
+                        requested: command.value,
+                        clamped_to,
+                    },
+                    false,
+                );
+            }
+        }
+        SvenCommand::CancelSequence => {
+            info!("Cancelling sequence");
+            SEQUENCE_CANCELLED.store(true, Ordering::Relaxed);
+        }
+        SvenCommand::Remind {
+            stand_after_min,
+            sit_after_min,
+        } => {
+            if stand_after_min == 0 || sit_after_min == 0 {
+                info!("Disabling reminders");
+                REMINDER.signal(None);
             } else {
-                sven_state.move_to_position(SvenPosition::Top).await;
+                info!(
+                    "Reminding to stand after {} min and sit after {} min",
+                    stand_after_min, sit_after_min
+                );
+                REMINDER.signal(Some((stand_after_min, sit_after_min)));
             }
-
-            let sven_position =
-                SvenPosition::try_from(command.value).unwrap_or(SvenPosition::Armrest);
-            sven_state.move_to_position(sven_position).await;
         }
+        SvenCommand::StartAutoToggle {
+            stand_minutes,
+            sit_minutes,
+        } => {
+            if stand_minutes == 0 || sit_minutes == 0 {
+                info!("Stopping auto toggle");
+                REMINDER.signal(None);
+            } else {
+                info!(
+                    "Auto toggling, standing {} min and sitting {} min",
+                    stand_minutes, sit_minutes
+                );
+                REMINDER.signal(Some((stand_minutes as u16, sit_minutes as u16)));
+            }
+        }
+        SvenCommand::StopAutoToggle => {
+            info!("Stopping auto toggle");
+            REMINDER.signal(None);
+        }
+        SvenCommand::Schedule { position, delay_ms } => {
+            info!("Scheduling move to {:?} in {} ms", position, delay_ms);
+            schedule_move(position, delay_ms)?;
+        }
+        SvenCommand::CancelSchedule => {
+            info!("Cancelling scheduled moves");
+            cancel_schedule();
+        }
+        SvenCommand::GetLog => {
+            // The whole log does not fit into one MQTT packet, publish it in
+            // pages of JSON arrays, oldest entries first
+            let entries: heapless::Vec<&MovementLogEntry, 16> =
+                sven_state.movement_log().oldest_ordered().collect();
+            if entries.is_empty() {
+                publish_json(SvenTopic::Log.as_str(), &entries, false);
+            }
+            for page in entries.chunks(MOVEMENT_LOG_PAGE_SIZE) {
+                publish_json(SvenTopic::Log.as_str(), &page, false);
+            }
+        }
+        SvenCommand::FactoryReset => {
+            let now = Instant::now();
+            let confirmed = FACTORY_RESET_REQUESTED.lock(|requested| {
+                matches!(
+                    requested.replace(Some(now)),
+                    Some(at) if now.duration_since(at).as_millis() <= FACTORY_RESET_CONFIRM_MS as u64
+                )
+            });
+            if !confirmed {
+                warn!(
+                    "Factory reset requested, send FactoryReset again within {} ms to confirm",
+                    FACTORY_RESET_CONFIRM_MS
+                );
+                return Ok(());
+            }
+            // WiFi credentials are compiled in, the NVS partition holds everything else
+            warn!("Factory reset confirmed, erasing NVS and restarting");
+            config_store.erase_all()?;
+            sleep(1000).await;
+            esp_hal::reset::software_reset();
+        }
+        SvenCommand::GetCalibration => {
+            publish_json(
+                SvenTopic::Calibration.as_str(),
+                &sven_state.calibration(),
+                false,
+            );
+        }
+        SvenCommand::SelfTest => {
+            if sven_state.is_locked() {
+                return Err(SvenError::DeskLocked);
+            }
+            let (pin_up_ok, pin_down_ok) = sven_state.self_test().await;
+            info!("Self test: up {}, down {}", pin_up_ok, pin_down_ok);
+            publish_json(
+                SvenTopic::SelfTest.as_str(),
+                &SelfTestMsg {
+                    pin_up_ok,
+                    pin_down_ok,
+                },
+                false,
+            );
+        }
+        SvenCommand::QueryCalibration => {
+            publish_json(
+                SvenTopic::CalibrationCurrent.as_str(),
+                &CalibrationMsg::new(sven_state),
+                false,
+            );
+        }
+        SvenCommand::Reboot => {
+            if !ALLOW_REMOTE_REBOOT {
+                return Err(SvenError::RebootDisabled);
+            }
+            warn!("Reboot requested, restarting in {} ms", REBOOT_DELAY_MS);
+            sven_state.save_to_nvs(config_store)?;
+            publish_json(SvenTopic::State.as_str(), &sven_state.to_msg(), true);
+            publish_json(SvenTopic::Status.as_str(), &"rebooting", false);
+            sleep(REBOOT_DELAY_MS).await;
+            esp_hal::reset::software_reset();
+        }
+        SvenCommand::GetOdometer => {
+            let total_distance_mm = sven_state.total_distance_mm();
+            publish_json(
+                SvenTopic::Odometer.as_str(),
+                &OdometerMsg {
+                    total_distance_mm,
+                    equivalent_km: total_distance_mm as f32 / 1_000_000.0,
+                },
+                false,
+            );
+        }
+        SvenCommand::GetFirmwareVersion => {
+            publish_json(
+                SvenTopic::Firmware.as_str(),
+                &FirmwareMsg {
+                    version: env!("CARGO_PKG_VERSION"),
+                    git_hash: env!("GIT_HASH"),
+                    build_ts: env!("BUILD_TIMESTAMP"),
+                },
+                false,
+            );
+        }
+        // Handled by the command executor, which tracks the last command
+        SvenCommand::GetDiagnostics => {}
+        _ => warn_on_limit(
+            sven_state
+                .handle_command(command, config_store, publish_progress)
+                .await,
+        )?,
     }
+    Ok(())
 }
@@ -0,0 +1,76 @@
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
+
+use embassy_time::Instant;
+use serde::Serialize;
+
+use crate::sven_state::SvenState;
+
+// Updated by the WiFi connection task, 0 until the first measurement
+static WIFI_RSSI_DBM: AtomicI32 = AtomicI32::new(0);
+
+pub fn set_wifi_rssi(rssi_dbm: i8) {
+    WIFI_RSSI_DBM.store(rssi_dbm as i32, Ordering::Relaxed);
+}
+
+// Commands rejected by the CommandRateLimit since boot
+static RATE_LIMITED_COMMANDS: AtomicU32 = AtomicU32::new(0);
+
+pub fn record_rate_limited() {
+    RATE_LIMITED_COMMANDS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Lowest free heap seen by HeapReport::new
+static MIN_FREE_HEAP_BYTES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// Heap usage, published to {DEVICE_ID}/heap
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapReport {
+    pub free_bytes: u32,
+    pub used_bytes: u32,
+}
+
+impl HeapReport {
+    // Also updates the low watermark reported in DiagnosticsReport
+    pub fn new() -> Self {
+        let free_bytes = esp_alloc::HEAP.free();
+        MIN_FREE_HEAP_BYTES.fetch_min(free_bytes, Ordering::Relaxed);
+        HeapReport {
+            free_bytes: free_bytes as u32,
+            used_bytes: esp_alloc::HEAP.used() as u32,
+        }
+    }
+}
+
+// Device health, published to {DEVICE_ID}/diagnostics
+#[derive(Debug, Serialize)]
+pub struct DiagnosticsReport {
+    pub wifi_rssi_dbm: i8,
+    pub free_heap_bytes: u32,
+    pub min_free_heap_bytes: u32,
+    pub uptime_s: u32,
+    pub total_movements: u32,
+    pub rate_limited_commands: u32,
+    pub last_command: heapless::String<32>,
+}
+
+impl DiagnosticsReport {
+    pub fn new(sven_state: &SvenState, last_command: &str) -> Self {
+        let mut command = heapless::String::new();
+        for c in last_command.chars() {
+            if command.push(c).is_err() {
+                break;
+            }
+        }
+        let heap = HeapReport::new();
+        DiagnosticsReport {
+            wifi_rssi_dbm: WIFI_RSSI_DBM.load(Ordering::Relaxed) as i8,
+            free_heap_bytes: heap.free_bytes,
+            min_free_heap_bytes: MIN_FREE_HEAP_BYTES.load(Ordering::Relaxed) as u32,
+            uptime_s: Instant::now().as_secs() as u32,
+            total_movements: sven_state.total_movements(),
+            rate_limited_commands: RATE_LIMITED_COMMANDS.load(Ordering::Relaxed),
+            last_command: command,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+// Parsers for the build time env vars read with option_env!, panicking at compile
+// time on invalid values
+
+pub const fn parse_u8(value: &str) -> u8 {
+    let bytes = value.as_bytes();
+    let mut result: u8 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "expected a number");
+        result = result * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    result
+}
+
+pub const fn parse_u32(value: &str) -> u32 {
+    let bytes = value.as_bytes();
+    let mut result: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "expected a number");
+        result = result * 10 + (bytes[i] - b'0') as u32;
+        i += 1;
+    }
+    result
+}
+
+pub const fn parse_usize(value: &str) -> usize {
+    let bytes = value.as_bytes();
+    let mut result: usize = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "expected a number");
+        result = result * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    result
+}
+
+pub const fn parse_bool(value: &str) -> bool {
+    match value.as_bytes() {
+        b"true" | b"1" => true,
+        b"false" | b"0" => false,
+        _ => panic!("expected true or false"),
+    }
+}
@@ -0,0 +1,111 @@
+use core::fmt;
+
+use crate::ota::OtaError;
+use crate::storage::StorageError;
+
+#[derive(Debug)]
+pub enum SvenError {
+    ParseError(serde_json_core::de::Error),
+    InvalidHeight(u32),
+    // limit_mm the movement would have crossed
+    LimitReached(u32),
+    InvalidPercentage(u32),
+    InvalidCalibration(u32, u32),
+    CalibrationTableFull,
+    ScheduleFull,
+    MovementAborted,
+    DeskLocked,
+    NetworkError,
+    InvalidSlot(u32),
+    EmptySlot(u8),
+    InvalidPin(u8),
+    RebootDisabled,
+    QueueFull,
+    InvalidClientId,
+    Storage(StorageError),
+    Ota(OtaError),
+}
+
+// Display is not available under defmt, so errors are logged with `{}` through this
+#[cfg(feature = "defmt")]
+impl defmt::Format for SvenError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            SvenError::ParseError(e) => {
+                defmt::write!(f, "failed to parse message: {:?}", defmt::Debug2Format(e))
+            }
+            SvenError::InvalidHeight(height_mm) => {
+                defmt::write!(f, "invalid height {} mm", height_mm)
+            }
+            SvenError::LimitReached(limit_mm) => {
+                defmt::write!(f, "movement would pass the limit at {} mm", limit_mm)
+            }
+            SvenError::InvalidPercentage(percent) => {
+                defmt::write!(f, "invalid percentage {}", percent)
+            }
+            SvenError::InvalidCalibration(ms, mm) => {
+                defmt::write!(f, "invalid calibration entry {} ms -> {} mm", ms, mm)
+            }
+            SvenError::CalibrationTableFull => defmt::write!(f, "calibration table is full"),
+            SvenError::ScheduleFull => defmt::write!(f, "too many scheduled moves"),
+            SvenError::MovementAborted => defmt::write!(f, "movement aborted"),
+            SvenError::DeskLocked => defmt::write!(f, "desk is locked"),
+            SvenError::NetworkError => defmt::write!(f, "network error"),
+            SvenError::InvalidSlot(slot) => defmt::write!(f, "invalid position slot {}", slot),
+            SvenError::EmptySlot(slot) => defmt::write!(f, "position slot {} is empty", slot),
+            SvenError::InvalidPin(pin) => defmt::write!(f, "invalid pin index {}", pin),
+            SvenError::RebootDisabled => defmt::write!(f, "remote reboot is disabled"),
+            SvenError::QueueFull => defmt::write!(f, "command queue full"),
+            SvenError::InvalidClientId => defmt::write!(f, "invalid MQTT client id"),
+            SvenError::Storage(e) => defmt::write!(f, "storage error: {:?}", e),
+            SvenError::Ota(e) => defmt::write!(f, "OTA update failed: {:?}", e),
+        }
+    }
+}
+
+impl fmt::Display for SvenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SvenError::ParseError(e) => write!(f, "failed to parse message: {:?}", e),
+            SvenError::InvalidHeight(height_mm) => write!(f, "invalid height {} mm", height_mm),
+            SvenError::LimitReached(limit_mm) => {
+                write!(f, "movement would pass the limit at {} mm", limit_mm)
+            }
+            SvenError::InvalidPercentage(percent) => write!(f, "invalid percentage {}", percent),
+            SvenError::InvalidCalibration(ms, mm) => {
+                write!(f, "invalid calibration entry {} ms -> {} mm", ms, mm)
+            }
+            SvenError::CalibrationTableFull => write!(f, "calibration table is full"),
+            SvenError::ScheduleFull => write!(f, "too many scheduled moves"),
+            SvenError::MovementAborted => write!(f, "movement aborted"),
+            SvenError::DeskLocked => write!(f, "desk is locked"),
+            SvenError::NetworkError => write!(f, "network error"),
+            SvenError::InvalidSlot(slot) => write!(f, "invalid position slot {}", slot),
+            SvenError::EmptySlot(slot) => write!(f, "position slot {} is empty", slot),
+            SvenError::InvalidPin(pin) => write!(f, "invalid pin index {}", pin),
+            SvenError::RebootDisabled => write!(f, "remote reboot is disabled"),
+            SvenError::QueueFull => write!(f, "command queue full"),
+            SvenError::InvalidClientId => write!(f, "invalid MQTT client id"),
+            SvenError::Storage(e) => write!(f, "storage error: {:?}", e),
+            SvenError::Ota(e) => write!(f, "OTA update failed: {:?}", e),
+        }
+    }
+}
+
+impl From<serde_json_core::de::Error> for SvenError {
+    fn from(e: serde_json_core::de::Error) -> Self {
+        SvenError::ParseError(e)
+    }
+}
+
+impl From<StorageError> for SvenError {
+    fn from(e: StorageError) -> Self {
+        SvenError::Storage(e)
+    }
+}
+
+impl From<OtaError> for SvenError {
+    fn from(e: OtaError) -> Self {
+        SvenError::Ota(e)
+    }
+}
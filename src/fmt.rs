@@ -0,0 +1,55 @@
+// Logging macros forwarding to either `log` or `defmt`, depending on the enabled
+// feature. Arguments are formatted with `{}` and `{:?}` only, so that the same
+// call sites work with both backends.
+#![allow(unused_macros)]
+
+macro_rules! log_with {
+    ($level:ident, $s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "log")]
+            ::log::$level!($s $(, $x)*);
+            #[cfg(feature = "defmt")]
+            ::defmt::$level!($s $(, $x)*);
+            #[cfg(not(any(feature = "log", feature = "defmt")))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        log_with!(debug, $($arg)*)
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        log_with!(info, $($arg)*)
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        log_with!(warn, $($arg)*)
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        log_with!(error, $($arg)*)
+    };
+}
+
+// Logs foreign types that only implement `Debug` under both backends
+#[cfg(feature = "defmt")]
+pub use defmt::Debug2Format;
+
+#[cfg(not(feature = "defmt"))]
+pub struct Debug2Format<'a, T: core::fmt::Debug + ?Sized>(pub &'a T);
+
+#[cfg(not(feature = "defmt"))]
+impl<T: core::fmt::Debug + ?Sized> core::fmt::Debug for Debug2Format<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.fmt(f)
+    }
+}
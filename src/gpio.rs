@@ -1,44 +1,198 @@
-use embassy_time::{Duration, Timer};
-use esp_hal::gpio::Output;
+use core::future::Future;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{AnyPin, Level, Output};
+
+// Shared between all pins so that an emergency stop can be raised from any task
+// while a pulse is in progress.
+static ABORT_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// The motor pins as driven by SvenState, implemented by PulsePin for GPIOs
+pub trait Pulse {
+    fn wait_for_cooldown(&self) -> impl Future<Output = ()>;
+
+    fn pulse(&mut self, duration: u32) -> impl Future<Output = u32>;
+
+    fn pulse_sequence(&mut self, sequence: &[(u32, u32)]) -> impl Future<Output = u32>;
+
+    fn test_toggle(&mut self, duration_ms: u32) -> impl Future<Output = bool>;
+}
 
 pub struct PulsePin<'d> {
     pin: Output<'d>,
     active_high: bool,
+    // Minimum rest between two pulses, see with_cooldown
+    cooldown_ms: u32,
+    last_pulse_ended_at: Option<Instant>,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PulsePin<'_> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "PulsePin {{ active_high: {}, cooldown_ms: {} }}",
+            self.active_high,
+            self.cooldown_ms
+        )
+    }
 }
 
 impl<'d> PulsePin<'d> {
     pub fn new(pin: Output<'d>, active_high: bool) -> Self {
-        Self { pin, active_high }
+        Self {
+            pin,
+            active_high,
+            cooldown_ms: 0,
+            last_pulse_ended_at: None,
+        }
     }
 
-    pub async fn pulse(&mut self, duration: u32) {
-        let duration = Duration::from_millis(duration as u64);
+    // Driven high while pulsing, `pin` should start low
+    pub fn active_high(pin: Output<'d>) -> Self {
+        Self::new(pin, true)
+    }
+
+    // Driven low while pulsing, e.g. for relay boards, `pin` should start high
+    pub fn active_low(pin: Output<'d>) -> Self {
+        Self::new(pin, false)
+    }
+
+    // Delays a pulse until `cooldown_ms` have passed since the previous one ended,
+    // so that repeated commands give the motor controller time to rest
+    pub fn with_cooldown(mut self, cooldown_ms: u32) -> Self {
+        self.cooldown_ms = cooldown_ms;
+        self
+    }
+
+    // Configures a type-erased pin as output, starting inactive
+    pub fn new_any(pin: AnyPin, active_high: bool) -> Self {
+        let inactive = if active_high { Level::Low } else { Level::High };
+        Self::new(Output::new(pin, inactive), active_high)
+    }
+
+    // Stop the pulse in progress, if any, driving its pin inactive immediately
+    pub fn abort() {
+        ABORT_SIGNAL.signal(());
+    }
+
+    pub async fn toggle_on(&mut self) {
         if self.active_high {
             self.pin.set_high();
         } else {
             self.pin.set_low();
         }
-        Timer::after(duration).await;
+    }
+
+    pub async fn toggle_off(&mut self) {
         if self.active_high {
             self.pin.set_low();
         } else {
             self.pin.set_high();
         }
     }
+}
 
-    pub async fn toggle_on(&mut self) {
-        if self.active_high {
-            self.pin.set_high();
-        } else {
-            self.pin.set_low();
+impl Pulse for PulsePin<'_> {
+    async fn wait_for_cooldown(&self) {
+        if let Some(ended_at) = self.last_pulse_ended_at {
+            let ready_at = ended_at + Duration::from_millis(self.cooldown_ms as u64);
+            if Instant::now() < ready_at {
+                debug!(
+                    "Cooling down for {} ms",
+                    (ready_at - Instant::now()).as_millis()
+                );
+                Timer::at(ready_at).await;
+            }
         }
     }
 
-    pub async fn toggle_off(&mut self) {
-        if self.active_high {
-            self.pin.set_low();
+    // Drive the pin active for `duration` ms, or until `abort` is called.
+    // Returns the number of ms the pin was actually active.
+    async fn pulse(&mut self, duration: u32) -> u32 {
+        self.wait_for_cooldown().await;
+        // Discard aborts raised while no pulse was running
+        ABORT_SIGNAL.reset();
+
+        let start = Instant::now();
+        self.toggle_on().await;
+        let aborted = matches!(
+            select(
+                Timer::after(Duration::from_millis(duration as u64)),
+                ABORT_SIGNAL.wait()
+            )
+            .await,
+            Either::Second(_)
+        );
+        self.toggle_off().await;
+        self.last_pulse_ended_at = Some(Instant::now());
+
+        if aborted {
+            let elapsed = start.elapsed().as_millis() as u32;
+            warn!("Pulse aborted after {} of {} ms", elapsed, duration);
+            return elapsed.min(duration);
+        }
+        duration
+    }
+
+    // Drive the pin through (on_ms, off_ms) steps, e.g. for controllers that
+    // expect several short pulses. `abort` stops the whole sequence.
+    // Returns the number of ms the pin was actually active.
+    async fn pulse_sequence(&mut self, sequence: &[(u32, u32)]) -> u32 {
+        self.wait_for_cooldown().await;
+        ABORT_SIGNAL.reset();
+
+        let mut active_ms = 0;
+        for &(on_ms, off_ms) in sequence {
+            let start = Instant::now();
+            self.toggle_on().await;
+            let aborted = matches!(
+                select(
+                    Timer::after(Duration::from_millis(on_ms as u64)),
+                    ABORT_SIGNAL.wait()
+                )
+                .await,
+                Either::Second(_)
+            );
+            self.toggle_off().await;
+            self.last_pulse_ended_at = Some(Instant::now());
+
+            if aborted {
+                active_ms += (start.elapsed().as_millis() as u32).min(on_ms);
+                warn!("Pulse sequence aborted after {} ms active", active_ms);
+                return active_ms;
+            }
+            active_ms += on_ms;
+
+            if let Either::Second(_) = select(
+                Timer::after(Duration::from_millis(off_ms as u64)),
+                ABORT_SIGNAL.wait(),
+            )
+            .await
+            {
+                warn!("Pulse sequence aborted after {} ms active", active_ms);
+                return active_ms;
+            }
+        }
+        active_ms
+    }
+
+    // Pulses for `duration_ms` and checks that the pin was driven inactive again,
+    // to verify the wiring of a new unit. False e.g. for a pin shorted to the
+    // other rail.
+    async fn test_toggle(&mut self, duration_ms: u32) -> bool {
+        self.pulse(duration_ms).await;
+        let inactive = if self.active_high {
+            Level::Low
         } else {
-            self.pin.set_high();
+            Level::High
+        };
+        let level = self.pin.output_level();
+        if level != inactive {
+            warn!("Pin still active after a {} ms test pulse", duration_ms);
         }
+        level == inactive
     }
 }
@@ -0,0 +1,135 @@
+use serde::Serialize;
+
+use crate::sven_state::{SvenState, SvenTopic, DEVICE_ID};
+
+// Home Assistant MQTT discovery, see https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+pub const NUMBER_CONFIG_TOPIC: &str = concat!(
+    "homeassistant/number/",
+    env!("SVEN_DEVICE_ID"),
+    "/height/config"
+);
+pub const PERCENT_NUMBER_CONFIG_TOPIC: &str = concat!(
+    "homeassistant/number/",
+    env!("SVEN_DEVICE_ID"),
+    "/height_percent/config"
+);
+pub const SENSOR_CONFIG_TOPIC: &str = concat!(
+    "homeassistant/sensor/",
+    env!("SVEN_DEVICE_ID"),
+    "/height/config"
+);
+const MANUFACTURER: &str = match option_env!("HA_MANUFACTURER") {
+    Some(manufacturer) => manufacturer,
+    None => "El-Maco",
+};
+const MODEL: &str = match option_env!("HA_MODEL") {
+    Some(model) => model,
+    None => "Sven ESP32",
+};
+const SW_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Serialize)]
+pub struct HaDevice {
+    identifiers: [&'static str; 1],
+    name: &'static str,
+    manufacturer: &'static str,
+    model: &'static str,
+    sw_version: &'static str,
+}
+
+impl HaDevice {
+    pub fn new() -> Self {
+        HaDevice {
+            identifiers: [DEVICE_ID],
+            name: "Sven",
+            manufacturer: MANUFACTURER,
+            model: MODEL,
+            sw_version: SW_VERSION,
+        }
+    }
+}
+
+// Number entity used to set the desk height
+#[derive(Debug, Serialize)]
+pub struct HaNumberConfig {
+    name: &'static str,
+    unique_id: &'static str,
+    command_topic: &'static str,
+    command_template: &'static str,
+    state_topic: &'static str,
+    value_template: &'static str,
+    availability_topic: &'static str,
+    min: u32,
+    max: u32,
+    unit_of_measurement: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    device: HaDevice,
+}
+
+impl HaNumberConfig {
+    pub fn height() -> Self {
+        HaNumberConfig {
+            name: "Height",
+            unique_id: concat!(env!("SVEN_DEVICE_ID"), "_height_number"),
+            command_topic: SvenTopic::Command.as_str(),
+            command_template: "{\"command\":\"AbsoluteHeight\",\"value\":{{ value | int }}}",
+            state_topic: SvenTopic::State.as_str(),
+            value_template: "{{ value_json.height_mm }}",
+            availability_topic: SvenTopic::Availability.as_str(),
+            min: SvenState::MIN_HEIGHT_MM,
+            max: SvenState::MAX_HEIGHT_MM,
+            unit_of_measurement: "mm",
+            device_class: Some("distance"),
+            device: HaDevice::new(),
+        }
+    }
+
+    // Same as `height` but as 0-100 %, the bounds in `value_template` must match
+    // SvenState::MIN_HEIGHT_MM and SvenState::MAX_HEIGHT_MM
+    pub fn height_percent() -> Self {
+        HaNumberConfig {
+            name: "Height percent",
+            unique_id: concat!(env!("SVEN_DEVICE_ID"), "_height_percent_number"),
+            command_topic: SvenTopic::Command.as_str(),
+            command_template: "{\"command\":\"AbsolutePercent\",\"value\":{{ value | int }}}",
+            state_topic: SvenTopic::State.as_str(),
+            value_template:
+                "{{ ((value_json.height_mm - 622) * 100 / (1274 - 622)) | round | int }}",
+            availability_topic: SvenTopic::Availability.as_str(),
+            min: 0,
+            max: 100,
+            unit_of_measurement: "%",
+            device_class: None,
+            device: HaDevice::new(),
+        }
+    }
+}
+
+// Sensor entity reporting the current desk height
+#[derive(Debug, Serialize)]
+pub struct HaSensorConfig {
+    name: &'static str,
+    unique_id: &'static str,
+    state_topic: &'static str,
+    value_template: &'static str,
+    availability_topic: &'static str,
+    unit_of_measurement: &'static str,
+    device_class: &'static str,
+    device: HaDevice,
+}
+
+impl HaSensorConfig {
+    pub fn height() -> Self {
+        HaSensorConfig {
+            name: "Height",
+            unique_id: concat!(env!("SVEN_DEVICE_ID"), "_height_sensor"),
+            state_topic: SvenTopic::State.as_str(),
+            value_template: "{{ value_json.height_mm }}",
+            availability_topic: SvenTopic::Availability.as_str(),
+            unit_of_measurement: "mm",
+            device_class: "distance",
+            device: HaDevice::new(),
+        }
+    }
+}
@@ -1,3 +1,16 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#[macro_use]
+mod fmt;
+
+pub mod diagnostics;
+pub mod env;
+pub mod error;
 pub mod gpio;
+pub mod ha_discovery;
+pub mod net_utils;
+pub mod ntp;
+pub mod ota;
+pub mod rate_limit;
+pub mod status_led;
+pub mod storage;
 pub mod sven_state;
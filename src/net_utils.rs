@@ -0,0 +1,82 @@
+use core::net::Ipv4Addr;
+
+// Whether `target` is on the same IPv4 network as `our`, given our prefix length
+pub fn same_subnet(our: Ipv4Addr, target: Ipv4Addr, prefix: u8) -> bool {
+    let mask = network_mask(prefix);
+    u32::from(our) & mask == u32::from(target) & mask
+}
+
+// !((1 << (32 - prefix)) - 1), without overflowing for /0
+fn network_mask(prefix: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix.min(32)))
+        .unwrap_or(0)
+}
+
+// base_ms * 2^attempt, capped at max_ms
+pub fn exponential_backoff_ms(attempt: u32, base_ms: u32, max_ms: u32) -> u32 {
+    base_ms
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(max_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_subnet_with_24_bit_prefix() {
+        let our = Ipv4Addr::new(192, 168, 1, 10);
+        assert!(same_subnet(our, Ipv4Addr::new(192, 168, 1, 200), 24));
+        assert!(!same_subnet(our, Ipv4Addr::new(192, 168, 2, 10), 24));
+    }
+
+    #[test]
+    fn same_subnet_with_25_bit_prefix() {
+        let our = Ipv4Addr::new(192, 168, 1, 10);
+        assert!(same_subnet(our, Ipv4Addr::new(192, 168, 1, 127), 25));
+        assert!(!same_subnet(our, Ipv4Addr::new(192, 168, 1, 128), 25));
+    }
+
+    #[test]
+    fn same_subnet_with_16_and_8_bit_prefix() {
+        let our = Ipv4Addr::new(10, 20, 30, 40);
+        assert!(same_subnet(our, Ipv4Addr::new(10, 20, 99, 1), 16));
+        assert!(!same_subnet(our, Ipv4Addr::new(10, 21, 30, 40), 16));
+        assert!(same_subnet(our, Ipv4Addr::new(10, 255, 0, 1), 8));
+        assert!(!same_subnet(our, Ipv4Addr::new(11, 20, 30, 40), 8));
+    }
+
+    #[test]
+    fn same_subnet_with_zero_and_full_prefix() {
+        let our = Ipv4Addr::new(192, 168, 1, 10);
+        assert!(same_subnet(our, Ipv4Addr::new(8, 8, 8, 8), 0));
+        assert!(same_subnet(our, our, 32));
+        assert!(!same_subnet(our, Ipv4Addr::new(192, 168, 1, 11), 32));
+    }
+
+    #[test]
+    fn backoff_starts_at_base() {
+        assert_eq!(exponential_backoff_ms(0, 1000, 60_000), 1000);
+    }
+
+    #[test]
+    fn backoff_doubles_per_attempt() {
+        assert_eq!(exponential_backoff_ms(1, 1000, 60_000), 2000);
+        assert_eq!(exponential_backoff_ms(2, 1000, 60_000), 4000);
+        assert_eq!(exponential_backoff_ms(5, 1000, 60_000), 32_000);
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max() {
+        assert_eq!(exponential_backoff_ms(6, 1000, 60_000), 60_000);
+        assert_eq!(exponential_backoff_ms(10, 1000, 60_000), 60_000);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        assert_eq!(exponential_backoff_ms(32, 1000, 60_000), 60_000);
+        assert_eq!(exponential_backoff_ms(u32::MAX, 1000, 60_000), 60_000);
+        assert_eq!(exponential_backoff_ms(40, u32::MAX, u32::MAX), u32::MAX);
+    }
+}
@@ -0,0 +1,85 @@
+use core::cell::Cell;
+
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant};
+
+const NTP_PORT: u16 = 123;
+const LOCAL_PORT: u16 = 50123;
+const NTP_PACKET_SIZE: usize = 48;
+// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_TO_UNIX_S: u64 = 2_208_988_800;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Unix time at boot, 0 until the first successful sync. There are no 64 bit
+// atomics on the ESP32-S3, hence the mutex.
+static BOOT_EPOCH_S: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(0));
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NtpError {
+    Dns,
+    Udp,
+    Timeout,
+    InvalidResponse,
+}
+
+// Current Unix time in seconds, or 0 if the time is unknown
+pub fn now_epoch_s() -> u64 {
+    let boot_epoch_s = BOOT_EPOCH_S.lock(|epoch| epoch.get());
+    if boot_epoch_s == 0 {
+        return 0;
+    }
+    boot_epoch_s + Instant::now().as_secs()
+}
+
+// Queries `server` once over SNTP and updates the clock, returning the Unix time
+pub async fn sync(stack: Stack<'_>, server: &str) -> Result<u64, NtpError> {
+    let address = *stack
+        .dns_query(server, DnsQueryType::A)
+        .await
+        .map_err(|_| NtpError::Dns)?
+        .first()
+        .ok_or(NtpError::Dns)?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; NTP_PACKET_SIZE * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; NTP_PACKET_SIZE * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(LOCAL_PORT).map_err(|_| NtpError::Udp)?;
+
+    // LI 0, version 3, mode 3 (client)
+    let mut request = [0u8; NTP_PACKET_SIZE];
+    request[0] = 0x1b;
+    socket
+        .send_to(&request, IpEndpoint::new(address, NTP_PORT))
+        .await
+        .map_err(|_| NtpError::Udp)?;
+
+    let mut response = [0u8; NTP_PACKET_SIZE];
+    let (len, _) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut response))
+        .await
+        .map_err(|_| NtpError::Timeout)?
+        .map_err(|_| NtpError::Udp)?;
+    if len < NTP_PACKET_SIZE {
+        return Err(NtpError::InvalidResponse);
+    }
+
+    // Seconds part of the transmit timestamp
+    let ntp_s = u32::from_be_bytes([response[40], response[41], response[42], response[43]]) as u64;
+    if ntp_s < NTP_TO_UNIX_S {
+        return Err(NtpError::InvalidResponse);
+    }
+    let epoch_s = ntp_s - NTP_TO_UNIX_S;
+    BOOT_EPOCH_S.lock(|boot_epoch_s| boot_epoch_s.set(epoch_s - Instant::now().as_secs()));
+    Ok(epoch_s)
+}
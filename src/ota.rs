@@ -0,0 +1,240 @@
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::{FlashStorage, FlashStorageError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Must match partitions.csv
+const OTADATA_OFFSET: u32 = 0xf000;
+const OTADATA_SECTOR_SIZE: u32 = 0x1000;
+const OTA_PARTITIONS: [u32; 2] = [0x20000, 0x200000];
+const OTA_PARTITION_SIZE: u32 = 0x1e0000;
+
+// esp_ota_select_entry_t as read by the ESP-IDF bootloader
+const OTA_SELECT_ENTRY_SIZE: usize = 32;
+const OTA_IMG_INVALID: u32 = 3;
+const OTA_IMG_ABORTED: u32 = 4;
+const OTA_IMG_UNDEFINED: u32 = u32::MAX;
+
+// JSON message on {DEVICE_ID}/ota/control starting an update
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OtaStartMsg<'a> {
+    pub size: u32,
+    pub sha256: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OtaStatusMsg {
+    pub progress: u8,
+}
+
+#[derive(Debug)]
+pub enum OtaError {
+    Flash(FlashStorageError),
+    InvalidSize(u32),
+    InvalidSha256,
+    TooMuchData,
+    VerificationFailed,
+    NotStarted,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for OtaError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            OtaError::Flash(e) => defmt::write!(f, "Flash({:?})", defmt::Debug2Format(e)),
+            OtaError::InvalidSize(size) => defmt::write!(f, "InvalidSize({})", size),
+            OtaError::InvalidSha256 => defmt::write!(f, "InvalidSha256"),
+            OtaError::TooMuchData => defmt::write!(f, "TooMuchData"),
+            OtaError::VerificationFailed => defmt::write!(f, "VerificationFailed"),
+            OtaError::NotStarted => defmt::write!(f, "NotStarted"),
+        }
+    }
+}
+
+impl From<FlashStorageError> for OtaError {
+    fn from(e: FlashStorageError) -> Self {
+        OtaError::Flash(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OtaSelectEntry {
+    seq: u32,
+    state: u32,
+    crc: u32,
+}
+
+impl OtaSelectEntry {
+    fn new(seq: u32) -> Self {
+        OtaSelectEntry {
+            seq,
+            state: OTA_IMG_UNDEFINED,
+            crc: Self::crc(seq),
+        }
+    }
+
+    fn crc(seq: u32) -> u32 {
+        esp_hal::rom::crc::crc32_le(u32::MAX, &seq.to_le_bytes())
+    }
+
+    fn from_bytes(bytes: &[u8; OTA_SELECT_ENTRY_SIZE]) -> Self {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        // [seq, seq_label[20], state, crc]
+        OtaSelectEntry {
+            seq: word(0),
+            state: word(24),
+            crc: word(28),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; OTA_SELECT_ENTRY_SIZE] {
+        let mut bytes = [0xffu8; OTA_SELECT_ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.state.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    // Same checks as the bootloader
+    fn is_valid(&self) -> bool {
+        self.seq != u32::MAX
+            && self.crc == Self::crc(self.seq)
+            && self.state != OTA_IMG_INVALID
+            && self.state != OTA_IMG_ABORTED
+    }
+}
+
+// Writes a new image to the OTA partition that is not currently booted
+pub struct OtaUpdater {
+    flash: FlashStorage,
+    target: usize,
+    size: u32,
+    written: u32,
+    sha256: [u8; 32],
+    hasher: Sha256,
+}
+
+impl OtaUpdater {
+    pub fn begin(start: &OtaStartMsg) -> Result<Self, OtaError> {
+        if start.size == 0 || start.size > OTA_PARTITION_SIZE {
+            return Err(OtaError::InvalidSize(start.size));
+        }
+        let sha256 = parse_sha256(start.sha256).ok_or(OtaError::InvalidSha256)?;
+
+        let mut flash = FlashStorage::new();
+        let (_, entry) = read_boot_entry(&mut flash)?;
+        // Without a valid entry the bootloader falls back to the first partition
+        let target = match entry {
+            Some(entry) => entry.seq as usize % OTA_PARTITIONS.len(),
+            None => 1,
+        };
+        info!(
+            "Starting OTA update of {} bytes to partition 0x{:x}",
+            start.size, OTA_PARTITIONS[target]
+        );
+        Ok(OtaUpdater {
+            flash,
+            target,
+            size: start.size,
+            written: 0,
+            sha256,
+            hasher: Sha256::new(),
+        })
+    }
+
+    // Returns the progress in percent
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<u8, OtaError> {
+        if self.written + chunk.len() as u32 > self.size {
+            return Err(OtaError::TooMuchData);
+        }
+        self.flash
+            .write(OTA_PARTITIONS[self.target] + self.written, chunk)?;
+        self.hasher.update(chunk);
+        self.written += chunk.len() as u32;
+        Ok(self.progress())
+    }
+
+    pub fn progress(&self) -> u8 {
+        (self.written as u64 * 100 / self.size as u64) as u8
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.written == self.size
+    }
+
+    // Verifies the image and selects it for the next boot. A corrupt image is
+    // invalidated so that it can never be booted.
+    pub fn finish(mut self) -> Result<(), OtaError> {
+        let digest: [u8; 32] = self.hasher.finalize().into();
+        if digest != self.sha256 {
+            warn!("OTA image checksum mismatch, invalidating partition");
+            let header = [0u8; 4];
+            self.flash.write(OTA_PARTITIONS[self.target], &header)?;
+            return Err(OtaError::VerificationFailed);
+        }
+
+        let (sector, entry) = read_boot_entry(&mut self.flash)?;
+        let mut seq = entry.map_or(0, |entry| entry.seq) + 1;
+        // The bootloader boots partition (seq - 1) % count
+        while (seq - 1) as usize % OTA_PARTITIONS.len() != self.target {
+            seq += 1;
+        }
+        let sector = match entry {
+            Some(_) => (sector + 1) % 2,
+            None => 0,
+        };
+        self.flash.write(
+            OTADATA_OFFSET + sector * OTADATA_SECTOR_SIZE,
+            &OtaSelectEntry::new(seq).to_bytes(),
+        )?;
+        info!(
+            "OTA partition 0x{:x} marked bootable",
+            OTA_PARTITIONS[self.target]
+        );
+        Ok(())
+    }
+}
+
+// The valid otadata entry with the highest sequence number and its sector
+fn read_boot_entry(flash: &mut FlashStorage) -> Result<(u32, Option<OtaSelectEntry>), OtaError> {
+    let mut boot = (0, None);
+    for sector in 0..2 {
+        let mut bytes = [0u8; OTA_SELECT_ENTRY_SIZE];
+        flash.read(OTADATA_OFFSET + sector * OTADATA_SECTOR_SIZE, &mut bytes)?;
+        let entry = OtaSelectEntry::from_bytes(&bytes);
+        if !entry.is_valid() {
+            continue;
+        }
+        match boot {
+            (_, Some(OtaSelectEntry { seq, .. })) if seq >= entry.seq => {}
+            _ => boot = (sector, Some(entry)),
+        }
+    }
+    Ok(boot)
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let nibble = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    };
+    let mut sha256 = [0u8; 32];
+    for (i, byte) in sha256.iter_mut().enumerate() {
+        *byte = nibble(hex[2 * i])? << 4 | nibble(hex[2 * i + 1])?;
+    }
+    Some(sha256)
+}
@@ -0,0 +1,98 @@
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+// Upper bound of max_per_second, the size of the timestamp window
+pub const MAX_COMMANDS_PER_SECOND: u8 = 16;
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Sliding one second window over the start times of executed commands, so that
+// a misbehaving automation can not keep the motor running nonstop
+pub struct CommandRateLimit {
+    max_per_second: u8,
+    timestamps: Deque<Instant, { MAX_COMMANDS_PER_SECOND as usize }>,
+}
+
+impl CommandRateLimit {
+    pub fn new(max_per_second: u8) -> Self {
+        CommandRateLimit {
+            max_per_second: max_per_second.clamp(1, MAX_COMMANDS_PER_SECOND),
+            timestamps: Deque::new(),
+        }
+    }
+
+    pub fn max_per_second(&self) -> u8 {
+        self.max_per_second
+    }
+
+    // Records a command at `now` if the limit allows it, otherwise returns the
+    // ms until the oldest command leaves the window
+    pub fn check(&mut self, now: Instant) -> Result<(), u32> {
+        while let Some(&oldest) = self.timestamps.front() {
+            if now.saturating_duration_since(oldest) < WINDOW {
+                break;
+            }
+            self.timestamps.pop_front();
+        }
+        if let Some(&oldest) = self.timestamps.front() {
+            if self.timestamps.len() >= self.max_per_second as usize {
+                let retry_after = WINDOW - now.saturating_duration_since(oldest);
+                return Err(retry_after.as_millis() as u32);
+            }
+        }
+        // Can not fail, the window holds at most max_per_second entries
+        self.timestamps.push_back(now).ok();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn max_per_second_is_clamped() {
+        assert_eq!(CommandRateLimit::new(0).max_per_second(), 1);
+        assert_eq!(CommandRateLimit::new(5).max_per_second(), 5);
+        assert_eq!(
+            CommandRateLimit::new(u8::MAX).max_per_second(),
+            MAX_COMMANDS_PER_SECOND
+        );
+    }
+
+    #[test]
+    fn rejects_commands_above_the_limit() {
+        let mut limit = CommandRateLimit::new(3);
+        assert_eq!(limit.check(at(0)), Ok(()));
+        assert_eq!(limit.check(at(100)), Ok(()));
+        assert_eq!(limit.check(at(200)), Ok(()));
+        // Retry once the command at 0 ms leaves the window
+        assert_eq!(limit.check(at(250)), Err(750));
+        assert_eq!(limit.check(at(999)), Err(1));
+    }
+
+    #[test]
+    fn oldest_command_leaves_the_window_after_one_second() {
+        let mut limit = CommandRateLimit::new(2);
+        assert_eq!(limit.check(at(0)), Ok(()));
+        assert_eq!(limit.check(at(500)), Ok(()));
+        assert_eq!(limit.check(at(999)), Err(1));
+        assert_eq!(limit.check(at(1000)), Ok(()));
+        // The window now holds 500 and 1000 ms
+        assert_eq!(limit.check(at(1200)), Err(300));
+        assert_eq!(limit.check(at(1500)), Ok(()));
+    }
+
+    #[test]
+    fn rejected_commands_do_not_extend_the_window() {
+        let mut limit = CommandRateLimit::new(1);
+        assert_eq!(limit.check(at(0)), Ok(()));
+        for ms in [100, 400, 900] {
+            assert_eq!(limit.check(at(ms)), Err(1000 - ms as u32));
+        }
+        assert_eq!(limit.check(at(1000)), Ok(()));
+    }
+}
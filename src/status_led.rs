@@ -0,0 +1,74 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::{Receiver, Watch};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{AnyPin, Level, Output};
+
+// Latest status to show, updated by the MQTT and movement code
+static STATUS: Watch<CriticalSectionRawMutex, BlinkPattern, 2> = Watch::new();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BlinkPattern {
+    // 1 Hz, WiFi connecting
+    Slow,
+    // 5 Hz, MQTT connecting
+    Fast,
+    // Connected and idle
+    Solid,
+    // Desk moving
+    DoublePulse,
+    // Last command failed
+    SOS,
+}
+
+pub fn set_status(pattern: BlinkPattern) {
+    STATUS.sender().send(pattern);
+}
+
+pub fn status_receiver() -> Option<Receiver<'static, CriticalSectionRawMutex, BlinkPattern, 2>> {
+    STATUS.receiver()
+}
+
+pub struct StatusLed<'d> {
+    pin: Output<'d>,
+}
+
+impl<'d> StatusLed<'d> {
+    pub fn new(pin: AnyPin) -> Self {
+        StatusLed {
+            pin: Output::new(pin, Level::Low),
+        }
+    }
+
+    // Shows one cycle of the pattern
+    pub async fn blink(&mut self, pattern: BlinkPattern) {
+        match pattern {
+            BlinkPattern::Slow => self.flash(500, 500).await,
+            BlinkPattern::Fast => self.flash(100, 100).await,
+            BlinkPattern::Solid => {
+                self.pin.set_high();
+                Timer::after(Duration::from_millis(1000)).await;
+            }
+            BlinkPattern::DoublePulse => {
+                self.flash(100, 100).await;
+                self.flash(100, 700).await;
+            }
+            BlinkPattern::SOS => {
+                for (on_ms, count) in [(200, 3), (600, 3), (200, 3)] {
+                    for _ in 0..count {
+                        self.flash(on_ms, 200).await;
+                    }
+                    Timer::after(Duration::from_millis(400)).await;
+                }
+                Timer::after(Duration::from_millis(800)).await;
+            }
+        }
+    }
+
+    async fn flash(&mut self, on_ms: u64, off_ms: u64) {
+        self.pin.set_high();
+        Timer::after(Duration::from_millis(on_ms)).await;
+        self.pin.set_low();
+        Timer::after(Duration::from_millis(off_ms)).await;
+    }
+}
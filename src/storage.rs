@@ -0,0 +1,170 @@
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::{FlashStorage, FlashStorageError};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::fmt::Debug2Format;
+
+// Location of the `nvs` partition, must match partitions.csv
+const NVS_OFFSET: u32 = 0x9000;
+const NVS_SIZE: u32 = 0x6000;
+
+// Every key owns a fixed size record: [magic, payload length, postcard payload...]
+const RECORD_SIZE: usize = 128;
+const RECORD_HEADER_SIZE: usize = 2;
+const RECORD_MAGIC: u8 = 0x5e;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NvsKey {
+    State,
+    Calibration,
+    // sven_custom_N
+    CustomPosition(u8),
+    Locked,
+    // (sitting, standing) height overrides
+    Positions,
+    // (base, max) MQTT reconnect delays in ms
+    ReconnectDelay,
+    // Up and down pins swapped for reversed wiring
+    DirectionsSwapped,
+    // Percentage applied to pulse durations
+    SpeedFactor,
+    // Total distance travelled in mm
+    Odometer,
+    // (min, max) heights in mm set with SvenCommand::SetLimits
+    Limits,
+    // Replaces the MAC based MQTT client id, see SvenCommand::SetMqttClientId
+    MqttClientId,
+}
+
+impl NvsKey {
+    fn slot(&self) -> u32 {
+        match self {
+            NvsKey::State => 0,
+            NvsKey::CustomPosition(slot) => 1 + *slot as u32,
+            NvsKey::Calibration => 9,
+            NvsKey::Locked => 10,
+            NvsKey::Positions => 11,
+            NvsKey::ReconnectDelay => 12,
+            NvsKey::DirectionsSwapped => 13,
+            NvsKey::SpeedFactor => 14,
+            NvsKey::Odometer => 15,
+            NvsKey::Limits => 16,
+            NvsKey::MqttClientId => 17,
+        }
+    }
+
+    fn offset(&self) -> u32 {
+        let offset = NVS_OFFSET + self.slot() * RECORD_SIZE as u32;
+        debug_assert!(offset + RECORD_SIZE as u32 <= NVS_OFFSET + NVS_SIZE);
+        offset
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Flash(FlashStorageError),
+    Serialize(postcard::Error),
+    InvalidSlot(u8),
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for StorageError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            StorageError::Flash(e) => defmt::write!(f, "Flash({:?})", Debug2Format(e)),
+            StorageError::Serialize(e) => defmt::write!(f, "Serialize({:?})", Debug2Format(e)),
+            StorageError::InvalidSlot(slot) => defmt::write!(f, "InvalidSlot({})", slot),
+        }
+    }
+}
+
+pub struct ConfigStore {
+    flash: FlashStorage,
+}
+
+impl ConfigStore {
+    pub fn new() -> Self {
+        ConfigStore {
+            flash: FlashStorage::new(),
+        }
+    }
+
+    // Returns None if the key has never been written or holds invalid data
+    pub fn read<T: DeserializeOwned>(&mut self, key: NvsKey) -> Option<T> {
+        let mut record = [0u8; RECORD_SIZE];
+        if let Err(e) = self.flash.read(key.offset(), &mut record) {
+            error!("Failed to read {:?} from NVS: {:?}", key, Debug2Format(&e));
+            return None;
+        }
+
+        if record[0] != RECORD_MAGIC {
+            return None;
+        }
+        let len = record[1] as usize;
+        if len > RECORD_SIZE - RECORD_HEADER_SIZE {
+            return None;
+        }
+        postcard::from_bytes(&record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len]).ok()
+    }
+
+    pub fn write<T: Serialize>(&mut self, key: NvsKey, value: &T) -> Result<(), StorageError> {
+        let mut record = [0xffu8; RECORD_SIZE];
+        let len = postcard::to_slice(value, &mut record[RECORD_HEADER_SIZE..])
+            .map_err(StorageError::Serialize)?
+            .len();
+        record[0] = RECORD_MAGIC;
+        record[1] = len as u8;
+
+        // Every write erases and rewrites the whole sector, skip it if the
+        // record is already stored
+        let mut stored = [0u8; RECORD_SIZE];
+        if self.flash.read(key.offset(), &mut stored).is_ok() && stored == record {
+            return Ok(());
+        }
+        self.flash
+            .write(key.offset(), &record)
+            .map_err(StorageError::Flash)
+    }
+
+    // Erases the whole nvs partition, every key reads as None afterwards
+    pub fn erase_all(&mut self) -> Result<(), StorageError> {
+        warn!(
+            "Erasing NVS at 0x{:x}..0x{:x}",
+            NVS_OFFSET,
+            NVS_OFFSET + NVS_SIZE
+        );
+        self.flash
+            .erase(NVS_OFFSET, NVS_OFFSET + NVS_SIZE)
+            .map_err(StorageError::Flash)
+    }
+}
+
+// User defined desk heights, stored in NVS so that they survive firmware updates
+pub struct CustomPositionStore<'a> {
+    store: &'a mut ConfigStore,
+}
+
+impl<'a> CustomPositionStore<'a> {
+    pub const SLOTS: u8 = 8;
+
+    pub fn new(store: &'a mut ConfigStore) -> Self {
+        CustomPositionStore { store }
+    }
+
+    pub fn save(&mut self, slot: u8, height_mm: u32) -> Result<(), StorageError> {
+        if slot >= Self::SLOTS {
+            return Err(StorageError::InvalidSlot(slot));
+        }
+        self.store.write(NvsKey::CustomPosition(slot), &height_mm)
+    }
+
+    // Returns None if the slot is out of range or has never been saved
+    pub fn load(&mut self, slot: u8) -> Option<u32> {
+        if slot >= Self::SLOTS {
+            return None;
+        }
+        self.store.read(NvsKey::CustomPosition(slot))
+    }
+}
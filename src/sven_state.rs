@@ -1,33 +1,144 @@
-use esp_hal::gpio::Input;
-use log::info;
+use core::cell::Cell;
+use core::future::Future;
+use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use heapless::HistoryBuffer;
 use serde::{Deserialize, Serialize};
 
-use crate::gpio::PulsePin;
+use crate::env::parse_u32;
+use crate::error::SvenError;
+use crate::gpio::{Pulse, PulsePin};
+use crate::ntp;
+use crate::storage::{ConfigStore, CustomPositionStore, NvsKey, StorageError};
+
+// DEVICE_ID env var, defaults to "sven" (see build.rs)
+pub const DEVICE_ID: &str = env!("SVEN_DEVICE_ID");
+
+// Time to travel the whole FULL_RANGE_MM into the end stop, HOMING_DURATION_MS
+// env var. Shorter homing pulses are scaled from it, see SvenState::homing_ms.
+const HOMING_DURATION_MS: u32 = match option_env!("HOMING_DURATION_MS") {
+    Some(ms) => parse_u32(ms),
+    None => 20_000,
+};
+const FULL_RANGE_MM: u32 = match option_env!("FULL_RANGE_MM") {
+    Some(mm) => parse_u32(mm),
+    None => 652,
+};
+const _: () = assert!(FULL_RANGE_MM > 0, "FULL_RANGE_MM must not be 0");
+
+// Pause between the chunks of a long relative movement, MOTOR_SETTLE_MS env var
+// at build time and SvenCommand::SetSettleMs at runtime
+static MOTOR_SETTLE_MS: AtomicU32 = AtomicU32::new(match option_env!("MOTOR_SETTLE_MS") {
+    Some(ms) => parse_u32(ms),
+    None => 500,
+});
+
+// Period of the unconditional state publishes in async_main, changed with
+// SvenCommand::SetPublishInterval
+static PUBLISH_INTERVAL_MS: AtomicU32 = AtomicU32::new(60_000);
+
+// Estimated height while a pulse is in progress, see SvenState::estimated_mm_from_pulse.
+// Published by the progress task in async_main.
+pub static PULSE_PROGRESS: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+const PULSE_PROGRESS_MS: u64 = 500;
+
+// Heights within this distance of a position count as that position,
+// POSITION_THRESHOLD_MM env var at build time and SvenCommand::SetPositionThreshold
+// at runtime
+static POS_THRESH_MM: AtomicU32 = AtomicU32::new(match option_env!("POSITION_THRESHOLD_MM") {
+    Some(mm) => parse_u32(mm),
+    None => 5,
+});
+
+// Builds a "{DEVICE_ID}/..." topic at compile time
+macro_rules! topic {
+    ($suffix:literal) => {
+        concat!(env!("SVEN_DEVICE_ID"), "/", $suffix)
+    };
+}
 
 pub enum SvenTopic {
+    // Subscription filter for every topic below, see async_main
+    All,
     Command,
+    CommandBinary,
     State,
     Status,
+    Availability,
+    Error,
+    Calibration,
+    Diagnostics,
+    OtaControl,
+    OtaData,
+    OtaStatus,
+    ConnectionStatus,
+    Progress,
+    Reminder,
+    Ack,
+    Log,
+    WifiRssi,
+    Warning,
+    Heap,
+    SequenceProgress,
+    Firmware,
+    Odometer,
+    CalibrationCurrent,
+    SelfTest,
+    CommandBatch,
+    BatchAck,
 }
 
 impl SvenTopic {
-    pub fn as_str(&self) -> &str {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            SvenTopic::Command => "sven/command",
-            SvenTopic::State => "sven/state",
-            SvenTopic::Status => "sven/status",
+            SvenTopic::All => topic!("#"),
+            SvenTopic::Command => topic!("command"),
+            SvenTopic::CommandBinary => topic!("command/binary"),
+            SvenTopic::State => topic!("state"),
+            SvenTopic::Status => topic!("status"),
+            SvenTopic::Availability => topic!("availability"),
+            SvenTopic::Error => topic!("error"),
+            SvenTopic::Calibration => topic!("calibration"),
+            SvenTopic::Diagnostics => topic!("diagnostics"),
+            SvenTopic::OtaControl => topic!("ota/control"),
+            SvenTopic::OtaData => topic!("ota/data"),
+            SvenTopic::OtaStatus => topic!("ota/status"),
+            SvenTopic::ConnectionStatus => topic!("connection_status"),
+            SvenTopic::Progress => topic!("progress"),
+            SvenTopic::Reminder => topic!("reminder"),
+            SvenTopic::Ack => topic!("ack"),
+            SvenTopic::Log => topic!("log"),
+            SvenTopic::WifiRssi => topic!("wifi_rssi"),
+            SvenTopic::Warning => topic!("warning"),
+            SvenTopic::Heap => topic!("heap"),
+            SvenTopic::SequenceProgress => topic!("sequence_progress"),
+            SvenTopic::Firmware => topic!("firmware"),
+            SvenTopic::Odometer => topic!("odometer"),
+            SvenTopic::CalibrationCurrent => topic!("calibration/current"),
+            SvenTopic::SelfTest => topic!("self_test"),
+            SvenTopic::CommandBatch => topic!("command/batch"),
+            SvenTopic::BatchAck => topic!("command/batch/ack"),
         }
     }
 }
 
-#[derive(Debug, Copy, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+// Discriminants must stay in sync with `TryFrom<u32>` since positions are sent
+// as integers in `DeskCommand::value`.
+#[derive(Debug, Copy, Serialize, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SvenPosition {
-    Bottom,
-    Top,
-    Armrest,
-    AboveArmrest,
-    Standing,
-    Custom,
+    Bottom = 0,
+    Top = 1,
+    Armrest = 2,
+    AboveArmrest = 3,
+    Standing = 4,
+    Custom = 5,
 }
 
 impl TryFrom<u32> for SvenPosition {
@@ -45,6 +156,128 @@ impl TryFrom<u32> for SvenPosition {
         }
     }
 }
+
+impl SvenPosition {
+    // Every position except Custom, from the bottom up
+    pub const NAMED: [SvenPosition; 5] = [
+        SvenPosition::Bottom,
+        SvenPosition::Armrest,
+        SvenPosition::AboveArmrest,
+        SvenPosition::Standing,
+        SvenPosition::Top,
+    ];
+
+    pub const fn all_variants() -> [SvenPosition; 6] {
+        [
+            SvenPosition::Bottom,
+            SvenPosition::Top,
+            SvenPosition::Armrest,
+            SvenPosition::AboveArmrest,
+            SvenPosition::Standing,
+            SvenPosition::Custom,
+        ]
+    }
+
+    // Default height of a named position, None for Custom. Sitting and standing
+    // can be overridden at runtime, see SvenState::get_position_mm.
+    pub const fn canonical_height(self) -> Option<u32> {
+        match self {
+            SvenPosition::Bottom => Some(SvenState::MIN_HEIGHT_MM),
+            SvenPosition::Armrest => Some(750),
+            SvenPosition::AboveArmrest => Some(795),
+            SvenPosition::Standing => Some(1140),
+            SvenPosition::Top => Some(SvenState::MAX_HEIGHT_MM),
+            SvenPosition::Custom => None,
+        }
+    }
+
+    // Variant name, as accepted by TryFrom<&str>
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            SvenPosition::Bottom => "Bottom",
+            SvenPosition::Top => "Top",
+            SvenPosition::Armrest => "Armrest",
+            SvenPosition::AboveArmrest => "AboveArmrest",
+            SvenPosition::Standing => "Standing",
+            SvenPosition::Custom => "Custom",
+        }
+    }
+
+    // Case-insensitive, underscores are ignored so that both "above_armrest"
+    // and "AboveArmrest" match. "sitting" is an alias of Armrest.
+    pub fn from_name(name: &str) -> Option<SvenPosition> {
+        const NAMES: [(&str, SvenPosition); 7] = [
+            ("bottom", SvenPosition::Bottom),
+            ("armrest", SvenPosition::Armrest),
+            ("sitting", SvenPosition::Armrest),
+            ("above_armrest", SvenPosition::AboveArmrest),
+            ("standing", SvenPosition::Standing),
+            ("top", SvenPosition::Top),
+            ("custom", SvenPosition::Custom),
+        ];
+        fn normalized(s: &str) -> impl Iterator<Item = u8> + '_ {
+            s.bytes()
+                .filter(|&b| b != b'_')
+                .map(|b| b.to_ascii_lowercase())
+        }
+        NAMES
+            .iter()
+            .find(|(n, _)| normalized(n).eq(normalized(name)))
+            .map(|&(_, position)| position)
+    }
+}
+
+impl core::fmt::Display for SvenPosition {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Positions are names in JSON, see from_name, and indices in postcard
+impl<'de> Deserialize<'de> for SvenPosition {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // postcard (NVS) stores the variant index, which equals the discriminant
+        if !deserializer.is_human_readable() {
+            let value = u32::deserialize(deserializer)?;
+            return SvenPosition::try_from(value).map_err(|_| {
+                serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Unsigned(value as u64),
+                    &"a position index",
+                )
+            });
+        }
+        // serde-json-core has no deserialize_any, so JSON only accepts names
+        deserializer.deserialize_str(SvenPositionVisitor)
+    }
+}
+
+struct SvenPositionVisitor;
+
+impl serde::de::Visitor<'_> for SvenPositionVisitor {
+    type Value = SvenPosition;
+
+    fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("a position name")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<SvenPosition, E> {
+        SvenPosition::from_name(v)
+            .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(v), &self))
+    }
+}
+
+// Ordered by canonical height. Custom has no fixed height and is unordered
+// against the named positions, so there is deliberately no (total) Ord.
+impl PartialOrd for SvenPosition {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        // Consistent with PartialEq, which considers Custom equal to itself
+        if self == other {
+            return Some(core::cmp::Ordering::Equal);
+        }
+        Some(self.canonical_height()?.cmp(&other.canonical_height()?))
+    }
+}
+
 impl TryFrom<&str> for SvenPosition {
     type Error = ();
 
@@ -56,46 +289,729 @@ impl TryFrom<&str> for SvenPosition {
             "AboveArmrest" => Ok(SvenPosition::AboveArmrest),
             "Standing" => Ok(SvenPosition::Standing),
             "Custom" => Ok(SvenPosition::Custom),
-            _ => panic!("Failed to parse {:?} to a SvenPosition", value),
+            _ => Err(()),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct SvenStateMsg {
     pub height_mm: u32,
     pub position: SvenPosition,
+    // Unix time of the message, 0 if unknown. Informative only, so it is never
+    // restored and older messages without it still parse.
+    #[serde(default, skip_deserializing)]
+    pub epoch_s: u64,
+    // Persisted under its own NVS key, see SvenState::set_locked
+    #[serde(default, skip_deserializing)]
+    pub locked: bool,
+    #[serde(default, skip_deserializing)]
+    pub moving: bool,
+    #[serde(default, skip_deserializing)]
+    pub uptime_s: u32,
+    #[serde(default, skip_deserializing)]
+    pub auto_toggle: AutoToggleStatus,
+    // DeskCommand::correlation_id of the command that led to this state
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<heapless::String<32>>,
 }
 
 impl SvenStateMsg {
-    pub fn new(sven_state: &SvenState) -> Self {
+    // Time-dependent fields (epoch_s, auto_toggle) are taken now, the rest from
+    // the snapshot
+    pub fn from_snapshot(s: &SvenSnapshot) -> Self {
         SvenStateMsg {
-            height_mm: sven_state.height_mm,
-            position: sven_state.position,
+            height_mm: s.height_mm,
+            position: s.position,
+            epoch_s: ntp::now_epoch_s(),
+            locked: s.locked,
+            moving: s.moving,
+            uptime_s: s.uptime_s,
+            auto_toggle: AutoToggleStatus::current(),
+            correlation_id: None,
         }
     }
 }
 
-pub struct SvenState<'d> {
+// Consistent view of the fields of SvenState, see SvenState::snapshot
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SvenSnapshot {
     pub height_mm: u32,
     pub position: SvenPosition,
-    pin_up: PulsePin<'d>,
-    pin_down: PulsePin<'d>,
-    button_up: Input<'d>,
-    button_down: Input<'d>,
-}
-
-impl<'d> SvenState<'d> {
-    const MIN_HEIGHT_MM: u32 = 622;
-    const MAX_HEIGHT_MM: u32 = 1274;
-    const POSITIONS_MM: &'static [(SvenPosition, u32)] = &[
-        (SvenPosition::Bottom, Self::MIN_HEIGHT_MM),
-        (SvenPosition::Armrest, 750),
-        (SvenPosition::AboveArmrest, 795),
-        (SvenPosition::Standing, 1140),
-        (SvenPosition::Top, Self::MAX_HEIGHT_MM),
-    ];
+    pub moving: bool,
+    pub locked: bool,
+    pub uptime_s: u32,
+}
+
+// Half of the StartAutoToggle cycle the desk is currently in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AutoTogglePhase {
+    Standing,
+    Sitting,
+}
+
+impl AutoTogglePhase {
+    pub fn next(self) -> Self {
+        match self {
+            AutoTogglePhase::Standing => AutoTogglePhase::Sitting,
+            AutoTogglePhase::Sitting => AutoTogglePhase::Standing,
+        }
+    }
+
+    // Position the desk moves to at the start of the phase
+    pub fn position(self) -> SvenPosition {
+        match self {
+            AutoTogglePhase::Standing => SvenPosition::Standing,
+            AutoTogglePhase::Sitting => SvenPosition::Armrest,
+        }
+    }
+
+    // Phase a manual move to `position` switches to, None for Custom
+    pub fn from_position(position: SvenPosition) -> Option<Self> {
+        match position {
+            SvenPosition::Standing | SvenPosition::Top => Some(AutoTogglePhase::Standing),
+            SvenPosition::Bottom | SvenPosition::Armrest | SvenPosition::AboveArmrest => {
+                Some(AutoTogglePhase::Sitting)
+            }
+            SvenPosition::Custom => None,
+        }
+    }
+}
+
+// Phase and end of the phase of the running auto toggle cycle, set by the
+// firmware's reminder task
+static AUTO_TOGGLE: BlockingMutex<
+    CriticalSectionRawMutex,
+    Cell<Option<(AutoTogglePhase, Instant)>>,
+> = BlockingMutex::new(Cell::new(None));
+
+pub fn set_auto_toggle(phase: Option<(AutoTogglePhase, Instant)>) {
+    AUTO_TOGGLE.lock(|auto_toggle| auto_toggle.set(phase));
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AutoToggleStatus {
+    pub running: bool,
+    pub phase: Option<AutoTogglePhase>,
+    // Until the desk moves to the next phase
+    pub remaining_s: u32,
+}
+
+impl AutoToggleStatus {
+    pub fn current() -> Self {
+        match AUTO_TOGGLE.lock(|auto_toggle| auto_toggle.get()) {
+            Some((phase, ends_at)) => AutoToggleStatus {
+                running: true,
+                phase: Some(phase),
+                remaining_s: ends_at.saturating_duration_since(Instant::now()).as_secs() as u32,
+            },
+            None => AutoToggleStatus::default(),
+        }
+    }
+}
+
+// WiFi and MQTT lifecycle, published on {DEVICE_ID}/connection_status
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionStatus {
+    WifiConnecting,
+    WifiConnected,
+    MqttConnecting,
+    MqttConnected,
+    MqttDisconnected,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionStatusMsg {
+    pub status: ConnectionStatus,
+    // Unix time of the transition, the message may be published much later
+    pub epoch_s: u64,
+}
+
+impl ConnectionStatusMsg {
+    pub fn new(status: ConnectionStatus) -> Self {
+        ConnectionStatusMsg {
+            status,
+            epoch_s: ntp::now_epoch_s(),
+        }
+    }
+}
+
+// Intermediate height during a movement, published on {DEVICE_ID}/progress
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SvenProgressMsg {
+    pub height_mm: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct SvenPositionMsg {
+    position: SvenPosition,
+    height_mm: u32,
+}
+
+// All named positions with their heights, serialized as a JSON array
+#[derive(Debug)]
+pub struct SvenPositionsMsg(PositionTable);
+
+impl SvenPositionsMsg {
+    pub fn new(sven_state: &SvenState) -> Self {
+        SvenPositionsMsg(sven_state.positions_mm.clone())
+    }
+}
+
+impl Serialize for SvenPositionsMsg {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|&(position, height_mm)| SvenPositionMsg {
+            position,
+            height_mm,
+        }))
+    }
+}
+
+// Positions and hold times of SvenCommand::MoveSequence
+pub const MAX_SEQUENCE_ENTRIES: usize = 8;
+pub type SequenceEntries = heapless::Vec<(SvenPosition, u32), MAX_SEQUENCE_ENTRIES>;
+
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SvenCommand {
+    UpDuration,        // value: ms
+    DownDuration,      // value: ms
+    UpRelative,        // value: mm
+    DownRelative,      // value: mm
+    AbsoluteHeight,    // value: mm
+    AbsolutePercent,   // value: % (0-100)
+    Position,          // value: SvenPosition
+    Calibrate,         // value: SvenPosition
+    EmergencyStop,     // value: ignored
+    SavePosition,      // value: slot (0-7)
+    RecallPosition,    // value: slot (0-7)
+    GetCalibration,    // value: ignored
+    Recalibrate,       // value: target height in mm after reaching the bottom, 0 to stay
+    GetDiagnostics,    // value: ignored
+    CancelSchedule,    // value: ignored
+    FactoryReset,      // value: ignored, must be sent twice within FACTORY_RESET_CONFIRM_MS
+    LockDesk,          // value: ignored
+    UnlockDesk,        // value: ignored
+    Toggle,            // value: ignored, stands up when sitting and vice versa
+    SetSettleMs,       // value: ms paused between movement chunks (100-5000)
+    GetLog,            // value: ignored
+    GetState,          // value: ignored
+    SetSittingHeight,  // value: mm, height of the Armrest position
+    SetStandingHeight, // value: mm, height of the Standing position
+    // Moves to `position` after `delay_ms`, e.g.
+    // {"command":{"Schedule":{"position":"Standing","delay_ms":1800000}}}
+    Schedule {
+        position: SvenPosition,
+        delay_ms: u32,
+    },
+    // Inserts or replaces a calibration entry, e.g.
+    // {"command":{"SetCalibration":{"ms":1000,"mm":9}}}
+    SetCalibration {
+        ms: u32,
+        mm: u32,
+    },
+    // Alternates between Standing and Armrest, 0 disables the reminders, e.g.
+    // {"command":{"Remind":{"stand_after_min":45,"sit_after_min":15}}}
+    Remind {
+        stand_after_min: u16,
+        sit_after_min: u16,
+    },
+    CancelSequence, // value: ignored
+    // Moves to each position in turn and holds it for the given ms, e.g.
+    // {"command":{"MoveSequence":{"entries":[["Standing",1800000],["Armrest",2700000]]}}}
+    MoveSequence {
+        entries: SequenceEntries,
+    },
+    // Bounds of the MQTT reconnect backoff, clamped to 500-10000 and 1000-300000, e.g.
+    // {"command":{"SetReconnectDelay":{"base_ms":2000,"max_ms":120000}}}
+    SetReconnectDelay {
+        base_ms: u32,
+        max_ms: u32,
+    },
+    IncrementalUp,      // value: ignored, moves by the smallest calibrated distance
+    IncrementalDown,    // value: ignored, moves by the smallest calibrated distance
+    GetFirmwareVersion, // value: ignored
+    SwapDirections,     // value: ignored, swaps the up and down pins
+    SetSpeedFactor,     // value: % applied to every pulse duration (50-150)
+    // Pulses pin 0 (up) or 1 (down) without tracking the height, for debugging, e.g.
+    // {"command":{"PulseRaw":{"pin_idx":1,"duration_ms":500}}}
+    PulseRaw {
+        pin_idx: u8,
+        duration_ms: u32,
+    },
+    GetOdometer,          // value: ignored
+    ResetOdometer,        // value: ignored
+    Reboot,               // value: ignored, unless disabled with ALLOW_REMOTE_REBOOT=false
+    SetPositionThreshold, // value: mm within which a height counts as a position (1-50)
+    // Narrows the heights AbsoluteHeight moves to, e.g. for a shelf above the desk
+    // {"command":{"SetLimits":{"min_mm":700,"max_mm":1150}}}
+    SetLimits {
+        min_mm: u32,
+        max_mm: u32,
+    },
+    // Measured distance of a pulse, e.g. after UpDuration 3000, scales the whole
+    // calibration table to match {"command":{"RecordObservation":{"ms":3000,"actual_mm":76}}}
+    RecordObservation {
+        ms: u32,
+        actual_mm: u32,
+    },
+    QueryCalibration, // value: ignored
+    SelfTest,         // value: ignored, pulses up and then down for 500 ms each
+    // Alternates between Standing and Armrest like Remind, re-syncing when the desk
+    // is moved by hand, e.g. {"command":{"StartAutoToggle":{"stand_minutes":30,"sit_minutes":30}}}
+    StartAutoToggle {
+        stand_minutes: u8,
+        sit_minutes: u8,
+    },
+    StopAutoToggle, // value: ignored
+    // Period of the state publishes that keep Home Assistant sensors fresh, clamped
+    // to 5000-300000, e.g. {"command":{"SetPublishInterval":{"ms":30000}}}
+    SetPublishInterval {
+        ms: u32,
+    },
+    // Up to 23 letters, digits and hyphens, used from the next reconnect, e.g.
+    // {"command":{"SetMqttClientId":{"id":"desk-office"}}}
+    SetMqttClientId {
+        id: heapless::String<32>,
+    },
+}
+
+impl SvenCommand {
+    // Variants are encoded by their index in this enum, so new commands must be
+    // appended to keep existing binary clients working
+    pub fn try_from_postcard(data: &[u8]) -> Result<DeskCommand, postcard::Error> {
+        postcard::from_bytes(data)
+    }
+
+    // Commands that drive the desk, rejected while it is locked. Scheduled moves
+    // are rejected when they are due.
+    pub fn is_movement(&self) -> bool {
+        matches!(
+            self,
+            SvenCommand::UpDuration
+                | SvenCommand::DownDuration
+                | SvenCommand::UpRelative
+                | SvenCommand::DownRelative
+                | SvenCommand::AbsoluteHeight
+                | SvenCommand::AbsolutePercent
+                | SvenCommand::Position
+                | SvenCommand::Toggle
+                | SvenCommand::Calibrate
+                | SvenCommand::RecallPosition
+                | SvenCommand::Recalibrate
+                | SvenCommand::MoveSequence { .. }
+                | SvenCommand::IncrementalUp
+                | SvenCommand::IncrementalDown
+                | SvenCommand::PulseRaw { .. }
+                | SvenCommand::SelfTest
+        )
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeskCommand {
+    pub command: SvenCommand,
+    // Not needed by commands that carry their own fields
+    pub value: u32,
+    // Echoed in the state published after the command, so that clients can
+    // match it to their request
+    pub correlation_id: Option<heapless::String<32>>,
+}
+
+impl<'de> Deserialize<'de> for DeskCommand {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // postcard is not self-describing, binary commands always carry an integer
+        if !deserializer.is_human_readable() {
+            let command = BinaryDeskCommand::deserialize(deserializer)?;
+            return Ok(DeskCommand {
+                command: command.command,
+                value: command.value,
+                correlation_id: command.correlation_id,
+            });
+        }
+        let command = JsonDeskCommand::deserialize(deserializer)?;
+        Ok(DeskCommand {
+            command: command.command,
+            value: command_value(command.value, command.position)
+                .map_err(serde::de::Error::custom)?,
+            correlation_id: command.correlation_id,
+        })
+    }
+}
+
+// Field order of binary commands, see SvenCommand::try_from_postcard
+#[derive(Deserialize)]
+struct BinaryDeskCommand {
+    command: SvenCommand,
+    value: u32,
+    correlation_id: Option<heapless::String<32>>,
+}
+
+// serde-json-core can not deserialize a field that is either a number or a
+// string, so a position may be given by name in its own field instead of
+// `value`, e.g. {"command":"Position","position":"standing"}
+#[derive(Deserialize)]
+struct JsonDeskCommand {
+    command: SvenCommand,
+    #[serde(default)]
+    value: Option<u32>,
+    #[serde(default)]
+    position: Option<SvenPosition>,
+    #[serde(default)]
+    correlation_id: Option<heapless::String<32>>,
+}
+
+fn command_value(value: Option<u32>, position: Option<SvenPosition>) -> Result<u32, &'static str> {
+    match (value, position) {
+        (Some(_), Some(_)) => Err("expected either value or position"),
+        (_, Some(position)) => Ok(position as u32),
+        (value, None) => Ok(value.unwrap_or_default()),
+    }
+}
+
+// Elements of a batch on {DEVICE_ID}/command/batch, executed in order, e.g.
+// [{"command":"AbsoluteHeight","value":1140},{"delay_ms":300000},{"command":"AbsoluteHeight","value":750}]
+pub const MAX_BATCH_COMMANDS: usize = 8;
+pub type BatchCommands = heapless::Vec<BatchCommand, MAX_BATCH_COMMANDS>;
+
+#[derive(Deserialize, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(try_from = "BatchEntry")]
+pub enum BatchCommand {
+    Command(DeskCommand),
+    Delay { delay_ms: u32 },
+}
+
+// Both shapes of BatchCommand in one struct, serde can not tell untagged
+// variants apart without alloc
+#[derive(Deserialize)]
+struct BatchEntry {
+    #[serde(default)]
+    command: Option<SvenCommand>,
+    #[serde(default)]
+    value: Option<u32>,
+    #[serde(default)]
+    position: Option<SvenPosition>,
+    #[serde(default)]
+    correlation_id: Option<heapless::String<32>>,
+    #[serde(default)]
+    delay_ms: Option<u32>,
+}
+
+impl TryFrom<BatchEntry> for BatchCommand {
+    type Error = &'static str;
+
+    fn try_from(entry: BatchEntry) -> Result<Self, Self::Error> {
+        match (entry.command, entry.delay_ms) {
+            (Some(command), None) => Ok(BatchCommand::Command(DeskCommand {
+                command,
+                value: command_value(entry.value, entry.position)?,
+                correlation_id: entry.correlation_id,
+            })),
+            (None, Some(delay_ms)) => Ok(BatchCommand::Delay { delay_ms }),
+            _ => Err("expected either a command or delay_ms"),
+        }
+    }
+}
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MovementState {
+    Idle,
+    MovingUp { started_at_ms: u64, total_ms: u32 },
+    MovingDown { started_at_ms: u64, total_ms: u32 },
+    // Moving away from a Custom position to a known one
+    Homing,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MovementLogEntry {
+    pub direction: Direction,
+    // Time the pin was actually active
+    pub duration_ms: u32,
+    pub height_before_mm: u32,
+    pub height_after_mm: u32,
+    // Unix time, 0 if unknown
+    pub timestamp_s: u32,
+    // Percentage the requested duration was scaled by, see SvenState::set_speed_factor
+    pub speed_factor_percent: u32,
+}
+
+// The most recent pulses, oldest first with `oldest_ordered`
+pub type MovementLog = heapless::HistoryBuffer<MovementLogEntry, 16>;
+
+// Runtime copy of the canonical heights, see SvenState::set_position_height
+pub type PositionTable = heapless::Vec<(SvenPosition, u32), 8>;
+
+// (ms, mm) pairs sorted by ms, see SvenState::set_calibration
+pub type CalibrationTable = heapless::Vec<(u32, u32), 16>;
+
+// Measured (ms, mm) movements, see SvenState::record_movement_observation
+pub type ObservationTable = heapless::Vec<(u32, u32), 10>;
+
+// Pins indexed by SvenState::PIN_UP and PIN_DOWN, swapped for installations with
+// the motor wires reversed. Generic so that the mapping can be tested without GPIOs.
+struct DirectionPins<P> {
+    pins: [P; 2],
+    swapped: bool,
+}
+
+impl<P> DirectionPins<P> {
+    fn new(up: P, down: P) -> Self {
+        DirectionPins {
+            pins: [up, down],
+            swapped: false,
+        }
+    }
+
+    // Swapping twice undoes it
+    fn swap(&mut self) {
+        self.pins.swap(0, 1);
+        self.swapped = !self.swapped;
+    }
+
+    fn swapped(&self) -> bool {
+        self.swapped
+    }
+
+    // Applies a swap restored from NVS, a no-op if it is already in effect
+    fn set_swapped(&mut self, swapped: bool) {
+        if self.swapped != swapped {
+            self.swap();
+        }
+    }
+}
+
+impl<P> Index<usize> for DirectionPins<P> {
+    type Output = P;
+
+    fn index(&self, pin: usize) -> &P {
+        &self.pins[pin]
+    }
+}
+
+impl<P> IndexMut<usize> for DirectionPins<P> {
+    fn index_mut(&mut self, pin: usize) -> &mut P {
+        &mut self.pins[pin]
+    }
+}
+
+// The desk as driven through GPIOs
+pub type SvenState<'d> = SvenDesk<PulsePin<'d>>;
+
+// Generic over the pins so that command dispatch can be tested without GPIOs,
+// see SvenState for the firmware
+pub struct SvenDesk<P> {
+    // Atomic so that readers do not need to hold the state mutex
+    height_mm: AtomicU32,
+    pub position: SvenPosition,
+    movement_state: MovementState,
+    calibration: CalibrationTable,
+    positions_mm: PositionTable,
+    total_movements: u32,
+    movement_log: MovementLog,
+    // Rejects movement commands while set, see SvenState::set_locked
+    locked: AtomicBool,
+    position_changed: Signal<NoopRawMutex, SvenPosition>,
+    // Indexed by PIN_UP and PIN_DOWN, see swap_direction_pins
+    pins: DirectionPins<P>,
+    // Applied to every pulse duration, see set_speed_factor
+    speed_factor_percent: u32,
+    // Within MIN_HEIGHT_MM..=MAX_HEIGHT_MM, see move_to_height_bounded
+    configured_min_mm: u32,
+    configured_max_mm: u32,
+    // See height_changed_since_last_publish, None until the first publish
+    last_published_height_mm: Option<u32>,
+    publish_forced: bool,
+    // Height and position of the last state written to NVS, see save_to_nvs
+    saved_state: Option<(u32, SvenPosition)>,
+    // Distance travelled over the lifetime of the motor, see save_to_nvs
+    total_distance_mm: u32,
+    saved_distance_mm: u32,
+    // Latest height estimates after each pulse, see smoothed_height_mm
+    height_history: HistoryBuffer<u32, 4>,
+    // Not persisted, the calibration they were applied to is
+    observations: ObservationTable,
+}
+
+#[cfg(feature = "defmt")]
+impl<P: Pulse> defmt::Format for SvenDesk<P> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "SvenState {{ height_mm: {}, position: {:?}, movement_state: {:?} }}",
+            self.height_mm(),
+            self.position,
+            self.movement_state
+        )
+    }
+}
+
+// The first position within `threshold_mm` of `height_mm`, Custom if there is none
+fn classify_height(
+    positions: &[(SvenPosition, u32)],
+    height_mm: u32,
+    threshold_mm: u32,
+) -> SvenPosition {
+    positions
+        .iter()
+        .find(|&&(_, pos_height)| height_mm.abs_diff(pos_height) <= threshold_mm)
+        .map_or(SvenPosition::Custom, |&(pos, _)| pos)
+}
+
+// Position of a height passed to SvenState::set_known_height. A Custom position
+// at the height of a named one is reclassified.
+fn known_position(
+    positions: &[(SvenPosition, u32)],
+    height_mm: u32,
+    position: SvenPosition,
+    threshold_mm: u32,
+) -> Result<SvenPosition, SvenError> {
+    if !(SvenState::MIN_HEIGHT_MM..=SvenState::MAX_HEIGHT_MM).contains(&height_mm) {
+        return Err(SvenError::InvalidHeight(height_mm));
+    }
+    Ok(match position {
+        SvenPosition::Custom => classify_height(positions, height_mm, threshold_mm),
+        position => position,
+    })
+}
+
+// Direction of a movement from `from_mm` to `to_mm`, None if there is nothing to do
+pub fn direction_from_heights(from_mm: u32, to_mm: u32) -> Option<Direction> {
+    match to_mm.cmp(&from_mm) {
+        core::cmp::Ordering::Greater => Some(Direction::Up),
+        core::cmp::Ordering::Less => Some(Direction::Down),
+        core::cmp::Ordering::Equal => None,
+    }
+}
+
+// Linearly interpolate the distance travelled for a pulse of `ms` between the
+// two surrounding calibration entries. Durations shorter than the first entry
+// are interpolated from (0, 0).
+fn interpolated_mm(calibration: &[(u32, u32)], ms: u32) -> u32 {
+    let (last_ms, last_mm) = calibration.last().copied().unwrap_or((0, 0));
+    if ms > last_ms {
+        // +38 mm for each second above the end of the table
+        return last_mm + 38 * (ms - last_ms) / 1000;
+    }
+
+    match calibration.binary_search_by_key(&ms, |&(m, _)| m) {
+        Ok(i) => calibration[i].1,
+        Err(i) => {
+            let (prev_ms, prev_mm) = i.checked_sub(1).map_or((0, 0), |i| calibration[i]);
+            let (m, mm) = calibration[i];
+            prev_mm + (mm - prev_mm) * (ms - prev_ms) / (m - prev_ms)
+        }
+    }
+}
+
+// Free function so that it can be used while a pin of the state is borrowed
+fn estimated_mm_from_pulse(
+    calibration: &[(u32, u32)],
+    start_height: u32,
+    direction: Direction,
+    elapsed_ms: u32,
+) -> u32 {
+    let delta_mm = interpolated_mm(calibration, elapsed_ms);
+    match direction {
+        Direction::Up => SvenState::MAX_HEIGHT_MM.min(start_height.saturating_add(delta_mm)),
+        Direction::Down => SvenState::MIN_HEIGHT_MM.max(start_height.saturating_sub(delta_mm)),
+    }
+}
+
+// The table is checked at compile time, const panics cannot format values so
+// each message names the offending entry instead
+const _: () = validate_ms_to_cm(SvenState::MS_TO_CM);
+
+// Interpolation binary searches the table by both columns
+const fn validate_ms_to_cm(ms_to_mm: &[(u32, u32)]) {
+    assert!(!ms_to_mm.is_empty(), "MS_TO_CM must not be empty");
+    let mut i = 1;
+    while i < ms_to_mm.len() {
+        assert!(
+            ms_to_mm[i].0 > ms_to_mm[i - 1].0,
+            "MS_TO_CM ms values must be strictly increasing"
+        );
+        assert!(
+            ms_to_mm[i].1 >= ms_to_mm[i - 1].1,
+            "MS_TO_CM mm values must not decrease"
+        );
+        i += 1;
+    }
+}
+
+// Runtime counterpart of validate_ms_to_cm for tables restored from NVS. Both
+// columns must strictly increase from (0, 0), otherwise interpolated_mm
+// underflows and interpolated_ms_for_mm divides by zero.
+fn is_valid_calibration(calibration: &[(u32, u32)]) -> bool {
+    !calibration.is_empty()
+        && calibration
+            .iter()
+            .try_fold((0, 0), |(prev_ms, prev_mm), &(ms, mm)| {
+                (ms > prev_ms && mm > prev_mm).then_some((ms, mm))
+            })
+            .is_some()
+}
+
+impl<P: Pulse> SvenDesk<P> {
+    const PIN_UP: usize = 0;
+    const PIN_DOWN: usize = 1;
+
+    pub const MIN_HEIGHT_MM: u32 = 622;
+    pub const MAX_HEIGHT_MM: u32 = 1274;
+
+    // A nudge is a short pulse followed by a gap, repeated up to MAX_NUDGES times
+    const NUDGE_ON_MS: u32 = 200;
+    const NUDGE_OFF_MS: u32 = 50;
+    const MAX_NUDGES: usize = 16;
+    const NUDGE_SEQUENCE: [(u32, u32); Self::MAX_NUDGES] =
+        [(Self::NUDGE_ON_MS, Self::NUDGE_OFF_MS); Self::MAX_NUDGES];
+
+    // Bounds of the pause between the chunks of a long relative movement
+    pub const MIN_SETTLE_MS: u32 = 100;
+    pub const MAX_SETTLE_MS: u32 = 5000;
+    // Bounds of the percentage applied to pulse durations
+    pub const MIN_SPEED_FACTOR_PERCENT: u32 = 50;
+    pub const MAX_SPEED_FACTOR_PERCENT: u32 = 150;
+    const ODOMETER_SAVE_MM: u32 = 100;
+    // Bounds of the position threshold
+    pub const MIN_POS_THRESH_MM: u32 = 1;
+    pub const MAX_POS_THRESH_MM: u32 = 50;
+    // Bounds of the period of the unconditional state publishes
+    pub const MIN_PUBLISH_INTERVAL_MS: u32 = 5_000;
+    pub const MAX_PUBLISH_INTERVAL_MS: u32 = 300_000;
+    const MAX_CORRECTION_MM: u32 = 30;
+    const SELF_TEST_MS: u32 = 500;
+    // Observations further off the calibration than this are taken as typos
+    const MIN_OBSERVATION_PERMILLE: u32 = 500;
+    const MAX_OBSERVATION_PERMILLE: u32 = 2000;
+
+    // Slack on top of time_to_reach_height before a movement is aborted
+    const MOVEMENT_TIMEOUT_MARGIN_MS: u32 = 3000;
+
+    // Extra travel into an end stop when leaving a Custom position, whose
+    // height may be off
+    const HOMING_MARGIN_MM: u32 = 130;
+    // Pause after running into the bottom stop, see recalibrate_zero_at_bottom
+    const HOMING_SETTLE_MS: u32 = 1000;
+
+    // Default calibration, measured on the original desk
     const MS_TO_CM: &'static [(u32, u32)] = &[
         (1000, 9),
         (2000, 48),
@@ -109,208 +1025,1806 @@ impl<'d> SvenState<'d> {
         (10000, 347),
     ];
 
-    // Create a new SvenState instance with default position
-    // and height set to the armrest position.
-    pub async fn new(
-        pin_up: PulsePin<'d>,
-        pin_down: PulsePin<'d>,
-        button_up: Input<'d>,
-        button_down: Input<'d>,
+    // Create a new SvenState instance with an unknown height. Nothing is pulsed
+    // here, the desk is homed on the first move to a position.
+    pub async fn new(pin_up: P, pin_down: P) -> Self {
+        Self::new_uncalibrated(pin_up, pin_down, 0, SvenPosition::Custom)
+    }
+
+    // Create a new SvenState instance with a known height and position, e.g.
+    // restored from NVS, without pulsing the motor to home the desk.
+    pub fn new_uncalibrated(
+        pin_up: P,
+        pin_down: P,
+        height_mm: u32,
+        position: SvenPosition,
     ) -> Self {
-        SvenState {
-            height_mm: 0,
-            position: SvenPosition::Custom,
-            pin_up,
-            pin_down,
-            button_up,
-            button_down,
+        SvenDesk {
+            height_mm: AtomicU32::new(height_mm),
+            position,
+            movement_state: MovementState::Idle,
+            calibration: Self::default_calibration(),
+            positions_mm: Self::default_positions(),
+            total_movements: 0,
+            movement_log: MovementLog::new(),
+            locked: AtomicBool::new(false),
+            position_changed: Signal::new(),
+            pins: DirectionPins::new(pin_up, pin_down),
+            speed_factor_percent: 100,
+            configured_min_mm: Self::MIN_HEIGHT_MM,
+            configured_max_mm: Self::MAX_HEIGHT_MM,
+            last_published_height_mm: None,
+            publish_forced: false,
+            saved_state: None,
+            total_distance_mm: 0,
+            saved_distance_mm: 0,
+            height_history: HistoryBuffer::new(),
+            observations: ObservationTable::new(),
         }
     }
 
-    pub fn get_position_mm(&self, position: SvenPosition) -> u32 {
-        Self::POSITIONS_MM
-            .iter()
-            .find(|&&(pos, _)| pos == position)
-            .map_or(Self::MIN_HEIGHT_MM, |&(_, height)| height)
+    // Snapshot for publishing and persisting, see SvenStateMsg
+    pub fn to_msg(&self) -> SvenStateMsg {
+        SvenStateMsg::from_snapshot(&self.snapshot())
     }
 
-    fn get_position_from_height(&self) -> SvenPosition {
-        const POS_THRESH: u32 = 5;
-        let curr_height = self.height_mm;
-        let position = Self::POSITIONS_MM
-            .iter()
-            .find(|&&(_, pos_height)| {
-                (curr_height < pos_height + POS_THRESH) && (curr_height > pos_height - POS_THRESH)
-            })
-            .map_or(SvenPosition::Custom, |&(pos, _)| pos);
-        info!("New position: {}", position as u32);
-        position
+    // Reads the atomics in one critical section, so that the height and lock can
+    // not change between the fields
+    pub fn snapshot(&self) -> SvenSnapshot {
+        critical_section::with(|_| SvenSnapshot {
+            height_mm: self.height_mm.load(Ordering::SeqCst),
+            position: self.position,
+            moving: self.is_moving(),
+            locked: self.locked.load(Ordering::SeqCst),
+            uptime_s: Instant::now().as_secs() as u32,
+        })
     }
 
-    fn get_duration_mm(&self, ms: u32) -> u32 {
-        // handle 11s ->
-        let s = ms / 1000;
-        if s > 10 {
-            // +38 mm for each second above 10s
-            return 347 + 38 * (s - 10); // TODO: improve
+    // Returns the last persisted state, or None if the NVS slot is empty or invalid
+    pub fn load_from_nvs(store: &mut ConfigStore) -> Option<SvenStateMsg> {
+        let state: SvenStateMsg = store.read(NvsKey::State)?;
+        if !(Self::MIN_HEIGHT_MM..=Self::MAX_HEIGHT_MM).contains(&state.height_mm) {
+            return None;
         }
-        Self::MS_TO_CM
-            .iter()
-            .find(|&&(m, _)| (m / 1000) == (ms / 1000))
-            .map_or(0, |&(_, mm)| mm) // Convert cm to mm
+        Some(state)
     }
 
-    pub async fn move_to_position(&mut self, position: SvenPosition) {
-        match self.position {
-            SvenPosition::Custom => match position {
-                SvenPosition::Bottom => {
-                    self.move_down_relative(self.height_mm - Self::MIN_HEIGHT_MM)
-                        .await;
-                    self.move_down(5000).await;
-                }
-                SvenPosition::Top => {
-                    self.move_up_relative(Self::MAX_HEIGHT_MM - self.height_mm)
-                        .await;
-                    self.move_up(5000).await;
-                }
-                // As calibration
-                _ => {
-                    let target_height = self.get_position_mm(position);
-                    info!("Moving to custom position {:?} with target height {} mm", position, target_height);
-                    self.move_to_height(target_height).await;
-                }
-            },
-            _ => {}
+    // Only writes the state when the height or position changed since the last
+    // save, the other fields of SvenStateMsg are not restored
+    pub fn save_to_nvs(&mut self, store: &mut ConfigStore) -> Result<(), StorageError> {
+        let state = (self.height_mm(), self.position);
+        if self.saved_state != Some(state) {
+            store.write(NvsKey::State, &self.to_msg())?;
+            self.saved_state = Some(state);
         }
-
-        match self.position {
-            SvenPosition::Top => match position {
-                SvenPosition::Top => self.move_up(5000).await, // Move up just in case
-                SvenPosition::Standing => self.move_down(4300).await,
-                SvenPosition::AboveArmrest => self.move_down(13500).await,
-                SvenPosition::Armrest => self.move_down(14800).await,
-                SvenPosition::Bottom => self.move_down(20000).await,
-                _ => {}
-            },
-            SvenPosition::Armrest => match position {
-                SvenPosition::Bottom => self.move_down(5000).await,
-                SvenPosition::AboveArmrest => self.move_up(1920).await,
-                SvenPosition::Standing => self.move_up(11000).await,
-                SvenPosition::Top => self.move_up(16000).await,
-                _ => {}
-            },
-            SvenPosition::AboveArmrest => match position {
-                SvenPosition::Armrest => self.move_down(1900).await,
-                SvenPosition::Bottom => self.move_down(7000).await,
-                SvenPosition::Standing => self.move_up(9900).await,
-                SvenPosition::Top => self.move_up(15000).await,
-                _ => {}
-            },
-            SvenPosition::Standing => match position {
-                SvenPosition::Armrest => self.move_down(10800).await,
-                SvenPosition::AboveArmrest => self.move_down(9900).await,
-                SvenPosition::Bottom => self.move_down(15000).await,
-                SvenPosition::Top => self.move_up(5000).await,
-                _ => {}
-            },
-            SvenPosition::Bottom => match position {
-                SvenPosition::Armrest => self.move_up(4300).await,
-                SvenPosition::AboveArmrest => self.move_up(5300).await,
-                SvenPosition::Standing => self.move_up(15000).await,
-                SvenPosition::Top => self.move_up(20000).await,
-                _ => {}
-            },
-            _ => {}
+        // The odometer is batched to limit flash wear
+        if self.total_distance_mm - self.saved_distance_mm >= Self::ODOMETER_SAVE_MM {
+            store.write(NvsKey::Odometer, &self.total_distance_mm)?;
+            self.saved_distance_mm = self.total_distance_mm;
         }
-        self.position = position;
-        self.height_mm = self.get_position_mm(position);
+        Ok(())
     }
 
-    pub async fn move_up(&mut self, delta_ms: u32) {
-        info!("Moving up {} ms", delta_ms);
-        let delta_mm = self.get_duration_mm(delta_ms);
+    // Whether the state needs to be published, i.e. the height changed or
+    // mark_dirty was called. Assumes that the caller publishes when it returns true.
+    pub fn height_changed_since_last_publish(&mut self) -> bool {
+        let height_mm = self.height_mm();
+        let changed = self.publish_forced || self.last_published_height_mm != Some(height_mm);
+        self.last_published_height_mm = Some(height_mm);
+        self.publish_forced = false;
+        changed
+    }
 
-        self.pin_up.pulse(delta_ms).await;
-        self.height_mm = Self::MAX_HEIGHT_MM.min(self.height_mm.saturating_add(delta_mm));
-        self.position = self.get_position_from_height();
+    // Publishes the next state even if the height did not change, e.g. for
+    // GetState or when something other than the height changed
+    pub fn mark_dirty(&mut self) {
+        self.publish_forced = true;
     }
 
-    pub async fn move_down(&mut self, delta_ms: u32) {
-        info!("Moving down {} ms", delta_ms);
-        let delta_mm = self.get_duration_mm(delta_ms);
-        self.pin_down.pulse(delta_ms).await;
-        self.height_mm = Self::MIN_HEIGHT_MM.max(self.height_mm.saturating_sub(delta_mm));
-        self.position = self.get_position_from_height();
+    pub fn total_distance_mm(&self) -> u32 {
+        self.total_distance_mm
     }
 
-    pub async fn move_up_relative(&mut self, delta_mm: u32) {
-        let mut distance_left = delta_mm;
-        while distance_left > 0 {
-            // find the duration of the maximum distance that fits into the dinstance_left
-            let (max_duration, max_distance) = Self::MS_TO_CM
-                .iter()
-                .rev()
-                .find(|&&(_, mm)| mm <= distance_left)
-                .unwrap_or(&(0, 0));
-            if *max_duration == 0 {
-                break; // No more distance can be moved (within 9 mm)
-            }
-            info!("Moving up {} mm equates to {} ms", delta_mm, max_duration);
-            self.move_up(*max_duration).await;
-            embassy_time::Timer::after(embassy_time::Duration::from_millis(1000u64)).await;
-            distance_left = distance_left.saturating_sub(*max_distance);
-        }
+    // E.g. after replacing the motor
+    pub fn reset_odometer(&mut self, store: &mut ConfigStore) -> Result<(), StorageError> {
+        self.total_distance_mm = 0;
+        self.saved_distance_mm = 0;
+        store.write(NvsKey::Odometer, &0u32)
     }
 
-    pub async fn move_down_relative(&mut self, delta_mm: u32) {
-        let mut distance_left = delta_mm;
-        while distance_left > 0 {
-            // find the duration of the maximum distance that fits into the distance_left
-            let (max_duration, max_distance) = Self::MS_TO_CM
-                .iter()
-                .rev()
-                .find(|&&(_, mm)| mm <= distance_left)
-                .unwrap_or(&(0, 0));
-            if *max_duration == 0 {
-                break; // No more distance can be moved (within 9 mm)
-            }
-            info!("Moving down {} mm equates to {} ms", delta_mm, max_duration);
-            self.move_down(*max_duration).await;
-            embassy_time::Timer::after(embassy_time::Duration::from_millis(1000u64)).await;
-            distance_left = distance_left.saturating_sub(*max_distance);
+    // Restores the odometer saved in NVS, up to ODOMETER_SAVE_MM may have been lost
+    pub fn load_odometer(&mut self, store: &mut ConfigStore) {
+        if let Some(total_distance_mm) = store.read::<u32>(NvsKey::Odometer) {
+            info!("Restored odometer {} mm from NVS", total_distance_mm);
+            self.total_distance_mm = total_distance_mm;
+            self.saved_distance_mm = total_distance_mm;
         }
     }
 
-    pub async fn move_to_height(&mut self, height_mm: u32) {
-        info!(
-            "Moving from height {} mm to {} mm",
-            self.height_mm, height_mm
+    pub fn settle_ms(&self) -> u32 {
+        MOTOR_SETTLE_MS
+            .load(Ordering::Relaxed)
+            .clamp(Self::MIN_SETTLE_MS, Self::MAX_SETTLE_MS)
+    }
+
+    // Returns the value in effect after clamping
+    pub fn set_settle_ms(&self, ms: u32) -> u32 {
+        let ms = ms.clamp(Self::MIN_SETTLE_MS, Self::MAX_SETTLE_MS);
+        MOTOR_SETTLE_MS.store(ms, Ordering::Relaxed);
+        ms
+    }
+
+    pub fn publish_interval_ms(&self) -> u32 {
+        PUBLISH_INTERVAL_MS
+            .load(Ordering::Relaxed)
+            .clamp(Self::MIN_PUBLISH_INTERVAL_MS, Self::MAX_PUBLISH_INTERVAL_MS)
+    }
+
+    // Returns the value in effect after clamping
+    pub fn set_publish_interval(&self, ms: u32) -> u32 {
+        let ms = ms.clamp(Self::MIN_PUBLISH_INTERVAL_MS, Self::MAX_PUBLISH_INTERVAL_MS);
+        PUBLISH_INTERVAL_MS.store(ms, Ordering::Relaxed);
+        ms
+    }
+
+    pub fn position_threshold_mm(&self) -> u32 {
+        POS_THRESH_MM
+            .load(Ordering::Relaxed)
+            .clamp(Self::MIN_POS_THRESH_MM, Self::MAX_POS_THRESH_MM)
+    }
+
+    // Returns the value in effect after clamping
+    pub fn set_position_threshold(&self, mm: u32) -> u32 {
+        let mm = mm.clamp(Self::MIN_POS_THRESH_MM, Self::MAX_POS_THRESH_MM);
+        POS_THRESH_MM.store(mm, Ordering::Relaxed);
+        mm
+    }
+
+    pub fn speed_factor_percent(&self) -> u32 {
+        self.speed_factor_percent
+    }
+
+    // Scales every pulse, e.g. 110 % for a heavier load that moves less per ms, so
+    // that the calibration table stays valid. Returns the value in effect after
+    // clamping.
+    pub fn set_speed_factor(
+        &mut self,
+        percent: u32,
+        store: &mut ConfigStore,
+    ) -> Result<u32, StorageError> {
+        self.speed_factor_percent = percent.clamp(
+            Self::MIN_SPEED_FACTOR_PERCENT,
+            Self::MAX_SPEED_FACTOR_PERCENT,
         );
-        if height_mm == self.height_mm {
-            info!("Already at height {} mm", height_mm);
-            return; // Already at the desired height
-        }
+        store.write(NvsKey::SpeedFactor, &self.speed_factor_percent)?;
+        Ok(self.speed_factor_percent)
+    }
 
-        if height_mm > self.height_mm {
-            let delta_mm = height_mm.min(Self::MAX_HEIGHT_MM) - self.height_mm;
-            self.move_up_relative(delta_mm).await;
-        } else {
-            let delta_mm = self.height_mm - height_mm.max(Self::MIN_HEIGHT_MM);
-            self.move_down_relative(delta_mm).await;
+    // Restores the speed factor saved in NVS, 100 % if there is none
+    pub fn load_speed_factor(&mut self, store: &mut ConfigStore) {
+        if let Some(percent) = store.read::<u32>(NvsKey::SpeedFactor) {
+            info!("Restored speed factor {} % from NVS", percent);
+            self.speed_factor_percent = percent.clamp(
+                Self::MIN_SPEED_FACTOR_PERCENT,
+                Self::MAX_SPEED_FACTOR_PERCENT,
+            );
         }
     }
 
-    pub async fn handle_button_press(&mut self) {
-        if self.button_up.is_high() {
-            self.pin_up.toggle_on().await;
-            while self.button_up.is_high() {}
-            self.pin_up.toggle_off().await;
+    // Per-deployment limits within the mechanical range, e.g. to keep the desk
+    // clear of a shelf. Rejects an empty or out of range interval.
+    pub fn set_limits(
+        &mut self,
+        min_mm: u32,
+        max_mm: u32,
+        store: &mut ConfigStore,
+    ) -> Result<(), SvenError> {
+        if min_mm < Self::MIN_HEIGHT_MM || min_mm >= max_mm {
+            return Err(SvenError::InvalidHeight(min_mm));
+        }
+        if max_mm > Self::MAX_HEIGHT_MM {
+            return Err(SvenError::InvalidHeight(max_mm));
         }
+        self.configured_min_mm = min_mm;
+        self.configured_max_mm = max_mm;
+        store.write(NvsKey::Limits, &(min_mm, max_mm))?;
+        Ok(())
+    }
 
-        if self.button_down.is_high() {
-            self.pin_down.toggle_on().await;
-            while self.button_down.is_high() {}
-            self.pin_down.toggle_off().await;
+    // Restores the limits saved in NVS, the full range if there are none
+    pub fn load_limits(&mut self, store: &mut ConfigStore) {
+        if let Some((min_mm, max_mm)) = store.read::<(u32, u32)>(NvsKey::Limits) {
+            if Self::MIN_HEIGHT_MM <= min_mm && min_mm < max_mm && max_mm <= Self::MAX_HEIGHT_MM {
+                info!("Restored limits {} - {} mm from NVS", min_mm, max_mm);
+                self.configured_min_mm = min_mm;
+                self.configured_max_mm = max_mm;
+            } else {
+                warn!("Ignoring invalid limits {} - {} mm in NVS", min_mm, max_mm);
+            }
         }
     }
+
+    pub fn clamp_to_limits(&self, height_mm: u32) -> u32 {
+        height_mm.clamp(self.configured_min_mm, self.configured_max_mm)
+    }
+
+    // Requested duration to the time the pin is active
+    fn scaled_ms(&self, ms: u32) -> u32 {
+        (ms as u64 * self.speed_factor_percent as u64 / 100) as u32
+    }
+
+    // Time the pin was active to the duration that the calibration table applies to
+    fn unscaled_ms(&self, ms: u32) -> u32 {
+        (ms as u64 * 100 / self.speed_factor_percent as u64) as u32
+    }
+
+    pub fn height_mm(&self) -> u32 {
+        self.height_mm.load(Ordering::Relaxed)
+    }
+
+    pub fn set_height_mm(&self, height_mm: u32) {
+        self.height_mm.store(height_mm, Ordering::Relaxed);
+    }
+
+    // For tasks that only read the height and can share a reference to the atomic
+    pub fn height_mm_atomic(&self) -> &AtomicU32 {
+        &self.height_mm
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn set_locked(&self, locked: bool, store: &mut ConfigStore) -> Result<(), StorageError> {
+        self.locked.store(locked, Ordering::Relaxed);
+        store.write(NvsKey::Locked, &locked)
+    }
+
+    // For installations with the motor wires reversed, PIN_UP then drives the pin
+    // that was passed as `pin_down` and vice versa. Swapping twice undoes it.
+    pub fn swap_direction_pins(&mut self) {
+        self.pins.swap();
+    }
+
+    pub fn directions_swapped(&self) -> bool {
+        self.pins.swapped()
+    }
+
+    // Restores the swap saved in NVS, must run before the first movement
+    pub fn load_direction_swap(&mut self, store: &mut ConfigStore) {
+        if let Some(true) = store.read::<bool>(NvsKey::DirectionsSwapped) {
+            info!("Restored swapped direction pins from NVS");
+            self.pins.set_swapped(true);
+        }
+    }
+
+    // Restores the lock saved in NVS, the desk starts unlocked if there is none
+    pub fn load_lock(&mut self, store: &mut ConfigStore) {
+        if let Some(true) = store.read::<bool>(NvsKey::Locked) {
+            info!("Restored desk lock from NVS");
+            self.locked.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn default_positions() -> PositionTable {
+        SvenPosition::NAMED
+            .iter()
+            .filter_map(|&position| Some((position, position.canonical_height()?)))
+            .collect()
+    }
+
+    fn default_calibration() -> CalibrationTable {
+        CalibrationTable::from_slice(Self::MS_TO_CM).unwrap()
+    }
+
+    // Replaces the default calibration with the one saved in NVS, if any. A
+    // corrupt table is ignored and the default is kept.
+    pub fn load_calibration(&mut self, store: &mut ConfigStore) {
+        if let Some(calibration) = store.read::<CalibrationTable>(NvsKey::Calibration) {
+            if !is_valid_calibration(&calibration) {
+                warn!("Ignoring invalid calibration table in NVS");
+                return;
+            }
+            info!(
+                "Restored {} calibration entries from NVS",
+                calibration.len()
+            );
+            self.calibration = calibration;
+        }
+    }
+
+    // Replaces the default sitting and standing heights with the ones saved in NVS
+    pub fn load_positions(&mut self, store: &mut ConfigStore) {
+        if let Some((sitting_mm, standing_mm)) = store.read::<(u32, u32)>(NvsKey::Positions) {
+            info!(
+                "Restored sitting height {} mm, standing height {} mm from NVS",
+                sitting_mm, standing_mm
+            );
+            self.set_position_mm(SvenPosition::Armrest, sitting_mm);
+            self.set_position_mm(SvenPosition::Standing, standing_mm);
+        }
+    }
+
+    fn set_position_mm(&mut self, position: SvenPosition, height_mm: u32) {
+        if let Some(entry) = self.positions_mm.iter_mut().find(|(p, _)| *p == position) {
+            entry.1 = height_mm;
+        }
+    }
+
+    // Overrides the height of the sitting (Armrest) or standing position and
+    // persists both. Sitting must stay below standing.
+    pub fn set_position_height(
+        &mut self,
+        position: SvenPosition,
+        height_mm: u32,
+        store: &mut ConfigStore,
+    ) -> Result<(), SvenError> {
+        if !(Self::MIN_HEIGHT_MM..=Self::MAX_HEIGHT_MM).contains(&height_mm) {
+            return Err(SvenError::InvalidHeight(height_mm));
+        }
+        let (sitting_mm, standing_mm) = match position {
+            SvenPosition::Armrest => (height_mm, self.get_position_mm(SvenPosition::Standing)),
+            SvenPosition::Standing => (self.get_position_mm(SvenPosition::Armrest), height_mm),
+            _ => return Err(SvenError::InvalidHeight(height_mm)),
+        };
+        if sitting_mm >= standing_mm {
+            return Err(SvenError::InvalidHeight(height_mm));
+        }
+        info!("Setting {:?} height to {} mm", position, height_mm);
+        self.set_position_mm(position, height_mm);
+        self.position = self.get_position_from_height();
+        store.write(NvsKey::Positions, &(sitting_mm, standing_mm))?;
+        Ok(())
+    }
+
+    pub fn calibration(&self) -> &[(u32, u32)] {
+        &self.calibration
+    }
+
+    // Whether SetCalibration or RecordObservation changed the default table
+    pub fn calibration_is_modified(&self) -> bool {
+        self.calibration.as_slice() != Self::MS_TO_CM
+    }
+
+    // Duration of the first calibration entry, the shortest movement with a
+    // measured distance (1000 ms, 9 mm by default)
+    pub fn smallest_increment_ms(&self) -> u32 {
+        self.calibration.first().map_or(1000, |&(ms, _)| ms)
+    }
+
+    // Inserts or replaces the entry for `ms` and persists the table. Distances must
+    // keep increasing with the duration for the interpolation to work.
+    pub fn set_calibration(
+        &mut self,
+        ms: u32,
+        mm: u32,
+        store: &mut ConfigStore,
+    ) -> Result<(), SvenError> {
+        if ms == 0 || mm == 0 {
+            return Err(SvenError::InvalidCalibration(ms, mm));
+        }
+        let (i, replace) = match self.calibration.binary_search_by_key(&ms, |&(m, _)| m) {
+            Ok(i) => (i, true),
+            Err(i) => (i, false),
+        };
+        let below = i.checked_sub(1).map_or(0, |i| self.calibration[i].1);
+        let above = self
+            .calibration
+            .get(if replace { i + 1 } else { i })
+            .map_or(u32::MAX, |&(_, mm)| mm);
+        if mm <= below || mm >= above {
+            return Err(SvenError::InvalidCalibration(ms, mm));
+        }
+
+        // The table in memory only changes once it is stored
+        let mut calibration = self.calibration.clone();
+        if replace {
+            calibration[i] = (ms, mm);
+        } else {
+            calibration
+                .insert(i, (ms, mm))
+                .map_err(|_| SvenError::CalibrationTableFull)?;
+        }
+        store.write(NvsKey::Calibration, &calibration)?;
+        self.calibration = calibration;
+        Ok(())
+    }
+
+    // Records that a pulse of `ms` moved the desk `actual_mm`, as measured by the
+    // user. The oldest observation is dropped when the table is full.
+    pub fn record_movement_observation(
+        &mut self,
+        ms: u32,
+        actual_mm: u32,
+    ) -> Result<(), SvenError> {
+        let expected_mm = self.get_duration_mm(ms);
+        if ms == 0 || actual_mm == 0 || expected_mm == 0 {
+            return Err(SvenError::InvalidCalibration(ms, actual_mm));
+        }
+        let permille = actual_mm * 1000 / expected_mm;
+        if !(Self::MIN_OBSERVATION_PERMILLE..=Self::MAX_OBSERVATION_PERMILLE).contains(&permille) {
+            return Err(SvenError::InvalidCalibration(ms, actual_mm));
+        }
+        if self.observations.is_full() {
+            self.observations.remove(0);
+        }
+        // Can not fail, there is room after removing the oldest
+        self.observations.push((ms, actual_mm)).ok();
+        Ok(())
+    }
+
+    // Scales every calibration entry by the average ratio of observed to expected
+    // distance and persists the table. Observations are kept, so applying them
+    // again converges on the table that fits all of them on average. Returns the
+    // applied ratio in permille.
+    pub fn apply_calibration_observations(
+        &mut self,
+        store: &mut ConfigStore,
+    ) -> Result<u32, SvenError> {
+        if self.observations.is_empty() {
+            return Ok(1000);
+        }
+        let sum_permille: u32 = self
+            .observations
+            .iter()
+            .map(|&(ms, actual_mm)| actual_mm * 1000 / self.get_duration_mm(ms).max(1))
+            .sum();
+        let permille = sum_permille / self.observations.len() as u32;
+        let mut calibration = self.calibration.clone();
+        let mut below_mm = 0;
+        for (_, mm) in calibration.iter_mut() {
+            // Rounding must not break the increasing distances
+            *mm = ((*mm as u64 * permille as u64 / 1000) as u32).max(below_mm + 1);
+            below_mm = *mm;
+        }
+        store.write(NvsKey::Calibration, &calibration)?;
+        self.calibration = calibration;
+        Ok(permille)
+    }
+
+    pub fn get_position_mm(&self, position: SvenPosition) -> u32 {
+        self.positions_mm
+            .iter()
+            .find(|&&(pos, _)| pos == position)
+            .map_or(Self::MIN_HEIGHT_MM, |&(_, height)| height)
+    }
+
+    // Signed distance from the current height to `position`, positive when the desk
+    // has to move up. None for Custom, which has no height of its own.
+    pub fn distance_to_position(&self, position: SvenPosition) -> Option<i32> {
+        if position == SvenPosition::Custom {
+            return None;
+        }
+        Some(self.get_position_mm(position) as i32 - self.height_mm() as i32)
+    }
+
+    // Within the position threshold of `target_mm`
+    pub fn is_near_height(&self, target_mm: u32) -> bool {
+        self.height_mm().abs_diff(target_mm) <= self.position_threshold_mm()
+    }
+
+    // Also true when the height matches `position` but the tracked position
+    // drifted, e.g. after moving there by height
+    pub fn is_at_position(&self, position: SvenPosition) -> bool {
+        self.position == position
+            || (position != SvenPosition::Custom
+                && self.is_near_height(self.get_position_mm(position)))
+    }
+
+    // None for Custom and when the desk is already within the position threshold
+    pub fn direction_to_position(&self, position: SvenPosition) -> Option<Direction> {
+        let target_mm = self.get_position_mm(position);
+        if position == SvenPosition::Custom || self.is_near_height(target_mm) {
+            return None;
+        }
+        direction_from_heights(self.height_mm(), target_mm)
+    }
+
+    // Named positions with their current heights, lowest first
+    pub fn all_named_positions(&self) -> impl Iterator<Item = (SvenPosition, u32)> {
+        let mut positions = self.positions_mm.clone();
+        positions.sort_unstable_by_key(|&(_, height_mm)| height_mm);
+        positions.into_iter()
+    }
+
+    // Mean of the last height estimates, so that the position does not flip
+    // between two neighbours on the noise of short pulses
+    pub fn smoothed_height_mm(&self) -> u32 {
+        if self.height_history.is_empty() {
+            return self.height_mm();
+        }
+        self.height_history.as_slice().iter().sum::<u32>() / self.height_history.len() as u32
+    }
+
+    // A jump beyond the position threshold is a real movement rather than noise
+    // and restarts the history, otherwise moving between two positions would be
+    // classified from the mean of both
+    fn record_height(&mut self) {
+        let height_mm = self.height_mm();
+        if self.smoothed_height_mm().abs_diff(height_mm) > self.position_threshold_mm() {
+            self.height_history.clear();
+        }
+        self.height_history.write(height_mm);
+    }
+
+    fn get_position_from_height(&self) -> SvenPosition {
+        let position = classify_height(
+            &self.positions_mm,
+            self.smoothed_height_mm(),
+            self.position_threshold_mm(),
+        );
+        info!("New position: {}", position);
+        if position != self.position {
+            self.position_changed.signal(position);
+        }
+        position
+    }
+
+    fn last_calibration(&self) -> (u32, u32) {
+        self.calibration.last().copied().unwrap_or((0, 0))
+    }
+
+    fn get_duration_mm(&self, ms: u32) -> u32 {
+        interpolated_mm(&self.calibration, ms)
+    }
+
+    // Height after the first `elapsed_ms` of a pulse that started at `start_height`
+    pub fn estimated_mm_from_pulse(
+        &self,
+        start_height: u32,
+        direction: Direction,
+        elapsed_ms: u32,
+    ) -> u32 {
+        estimated_mm_from_pulse(&self.calibration, start_height, direction, elapsed_ms)
+    }
+
+    // Pulses `pin` for `delta_ms` scaled by the speed factor and signals the
+    // estimated height to PULSE_PROGRESS every PULSE_PROGRESS_MS. Returns the
+    // number of ms the pin was actually active, also scaled.
+    async fn pulse_with_progress(
+        &mut self,
+        pin: usize,
+        direction: Direction,
+        delta_ms: u32,
+    ) -> u32 {
+        let start_height = self.height_mm();
+        let scaled_ms = self.scaled_ms(delta_ms);
+        let speed_factor_percent = self.speed_factor_percent;
+        let calibration = &self.calibration;
+        let pin = &mut self.pins[pin];
+        // Start the clock when the pin goes active, not before the cooldown
+        pin.wait_for_cooldown().await;
+        let started_at = Instant::now();
+        let report = async {
+            loop {
+                embassy_time::Timer::after_millis(PULSE_PROGRESS_MS).await;
+                let elapsed_ms =
+                    (started_at.elapsed().as_millis() * 100 / speed_factor_percent as u64) as u32;
+                PULSE_PROGRESS.signal(estimated_mm_from_pulse(
+                    calibration,
+                    start_height,
+                    direction,
+                    elapsed_ms,
+                ));
+            }
+        };
+        match select(pin.pulse(scaled_ms), report).await {
+            Either::First(pulsed_ms) => pulsed_ms,
+            Either::Second(_) => unreachable!(),
+        }
+    }
+
+    // Inverse of `get_duration_mm`: the pulse length needed to move `target_mm`
+    pub fn interpolated_ms_for_mm(&self, target_mm: u32) -> u32 {
+        let (last_ms, last_mm) = self.last_calibration();
+        if target_mm > last_mm {
+            // 38 mm for each second above the end of the table
+            return last_ms + (target_mm - last_mm) * 1000 / 38;
+        }
+
+        match self
+            .calibration
+            .binary_search_by_key(&target_mm, |&(_, mm)| mm)
+        {
+            Ok(i) => self.calibration[i].0,
+            Err(i) => {
+                let (prev_ms, prev_mm) = i.checked_sub(1).map_or((0, 0), |i| self.calibration[i]);
+                let (ms, mm) = self.calibration[i];
+                prev_ms + (ms - prev_ms) * (target_mm - prev_mm) / (mm - prev_mm)
+            }
+        }
+    }
+
+    // Estimated duration of moving from the current height to `target_mm`,
+    // including the pauses between chunks
+    pub fn time_to_reach_height(&self, target_mm: u32) -> u32 {
+        let (max_ms, max_mm) = self.last_calibration();
+        if max_mm == 0 {
+            return 0;
+        }
+        let distance_mm = target_mm.abs_diff(self.height_mm());
+        let chunks = distance_mm.saturating_sub(1) / max_mm;
+        let remaining_mm = distance_mm - chunks * max_mm;
+        self.scaled_ms(chunks * max_ms + self.interpolated_ms_for_mm(remaining_mm))
+            + chunks * self.settle_ms()
+    }
+
+    pub async fn move_to_position(&mut self, position: SvenPosition) -> Result<(), SvenError> {
+        // Only an uninitialized desk needs the full homing, a Custom position
+        // restored from NVS comes with a usable height
+        if self.position == SvenPosition::Custom && self.height_mm() == 0 {
+            self.recalibrate_zero_at_bottom().await?;
+        }
+        if self.is_at_position(position) {
+            info!("Already at position {:?}", position);
+            if self.position != position {
+                self.position = position;
+                self.position_changed.signal(position);
+            }
+            return Ok(());
+        }
+
+        if self.position == SvenPosition::Custom {
+            self.movement_state = MovementState::Homing;
+            let homed = self.move_from_custom(position).await;
+            self.movement_state = MovementState::Idle;
+            homed?;
+        } else if position != SvenPosition::Custom {
+            // Custom has no height of its own, there is nothing to move to
+            self.move_to_height(self.get_position_mm(position)).await?;
+        }
+        self.position = position;
+        self.set_height_mm(self.get_position_mm(position));
+        self.position_changed.signal(position);
+        Ok(())
+    }
+
+    // Overrides the tracked height and position without moving, e.g. after the
+    // desk was moved by hand to a measured height. Heights outside
+    // MIN_HEIGHT_MM..=MAX_HEIGHT_MM are rejected.
+    pub fn set_known_height(
+        &mut self,
+        height_mm: u32,
+        position: SvenPosition,
+    ) -> Result<(), SvenError> {
+        let position = known_position(
+            &self.positions_mm,
+            height_mm,
+            position,
+            self.position_threshold_mm(),
+        )?;
+        info!("Setting known height {} mm at {:?}", height_mm, position);
+        self.set_height_mm(height_mm);
+        self.height_history.clear();
+        if self.position != position {
+            self.position = position;
+            self.position_changed.signal(position);
+        }
+        Ok(())
+    }
+
+    // Homing pulse for `distance_mm`, scaled from HOMING_DURATION_MS
+    fn homing_ms(distance_mm: u32) -> u32 {
+        (HOMING_DURATION_MS as u64 * distance_mm as u64 / FULL_RANGE_MM as u64) as u32
+    }
+
+    // The restored height of a Custom position may have drifted, so Bottom and Top
+    // overrun into the end stop by HOMING_MARGIN_MM
+    async fn move_from_custom(&mut self, position: SvenPosition) -> Result<(), SvenError> {
+        match position {
+            SvenPosition::Bottom => {
+                self.move_down_relative(self.height_mm() - Self::MIN_HEIGHT_MM)
+                    .await?;
+                self.move_down(Self::homing_ms(Self::HOMING_MARGIN_MM))
+                    .await
+            }
+            SvenPosition::Top => {
+                self.move_up_relative(Self::MAX_HEIGHT_MM - self.height_mm())
+                    .await?;
+                self.move_up(Self::homing_ms(Self::HOMING_MARGIN_MM)).await
+            }
+            // As calibration
+            _ => {
+                let target_height = self.get_position_mm(position);
+                info!(
+                    "Moving to custom position {:?} with target height {} mm",
+                    position, target_height
+                );
+                self.move_to_height(target_height).await
+            }
+        }
+    }
+
+    // Resolves with the latest position once it changes. Only the most recent
+    // change is kept, intermediate positions may be skipped.
+    pub fn wait_for_position_change(&self) -> impl Future<Output = SvenPosition> + '_ {
+        self.position_changed.wait()
+    }
+
+    pub fn movement_state(&self) -> MovementState {
+        self.movement_state
+    }
+
+    // Number of pulses started since boot
+    pub fn total_movements(&self) -> u32 {
+        self.total_movements
+    }
+
+    // Below the midpoint between Armrest and Standing
+    pub fn is_sitting(&self) -> bool {
+        let midpoint_mm = (self.get_position_mm(SvenPosition::Armrest)
+            + self.get_position_mm(SvenPosition::Standing))
+            / 2;
+        self.height_mm() < midpoint_mm
+    }
+
+    pub fn movement_log(&self) -> &MovementLog {
+        &self.movement_log
+    }
+
+    fn log_movement(&mut self, direction: Direction, duration_ms: u32, height_before_mm: u32) {
+        self.total_distance_mm = self
+            .total_distance_mm
+            .saturating_add(height_before_mm.abs_diff(self.height_mm()));
+        self.movement_log.write(MovementLogEntry {
+            direction,
+            duration_ms,
+            height_before_mm,
+            height_after_mm: self.height_mm(),
+            timestamp_s: ntp::now_epoch_s() as u32,
+            speed_factor_percent: self.speed_factor_percent,
+        });
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.movement_state != MovementState::Idle
+    }
+
+    // Remaining time of the pulse in progress, 0 when not pulsing
+    pub fn estimated_time_remaining_ms(&self) -> u32 {
+        match self.movement_state {
+            MovementState::MovingUp {
+                started_at_ms,
+                total_ms,
+            }
+            | MovementState::MovingDown {
+                started_at_ms,
+                total_ms,
+            } => {
+                let elapsed_ms = Instant::now().as_millis().saturating_sub(started_at_ms);
+                (total_ms as u64).saturating_sub(elapsed_ms) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    // Guards against starting a pulse while another one is in progress. Homing is
+    // made up of several pulses, so it is allowed to continue.
+    fn begin_movement(
+        &mut self,
+        movement_state: MovementState,
+    ) -> Result<MovementState, SvenError> {
+        let previous = self.movement_state;
+        match previous {
+            MovementState::Idle | MovementState::Homing => {
+                self.movement_state = movement_state;
+                self.total_movements = self.total_movements.wrapping_add(1);
+                Ok(previous)
+            }
+            _ => Err(SvenError::MovementAborted),
+        }
+    }
+
+    pub async fn move_up(&mut self, delta_ms: u32) -> Result<(), SvenError> {
+        info!("Moving up {} ms at {}", delta_ms, ntp::now_epoch_s());
+        let previous = self.begin_movement(MovementState::MovingUp {
+            started_at_ms: Instant::now().as_millis(),
+            total_ms: self.scaled_ms(delta_ms),
+        })?;
+        let pulsed_ms = self
+            .pulse_with_progress(Self::PIN_UP, Direction::Up, delta_ms)
+            .await;
+        self.movement_state = previous;
+        let delta_mm = self.get_duration_mm(self.unscaled_ms(pulsed_ms));
+
+        let height_before_mm = self.height_mm();
+        self.set_height_mm(Self::MAX_HEIGHT_MM.min(self.height_mm().saturating_add(delta_mm)));
+        self.record_height();
+        self.position = self.get_position_from_height();
+        self.log_movement(Direction::Up, pulsed_ms, height_before_mm);
+        if pulsed_ms < self.scaled_ms(delta_ms) {
+            return Err(SvenError::MovementAborted);
+        }
+        Ok(())
+    }
+
+    // As move_up, but shortens the pulse so that the desk stops at the limit set
+    // with SetLimits. move_up itself is not bounded, homing relies on running
+    // into the end stop.
+    pub async fn move_up_bounded(&mut self, delta_ms: u32) -> Result<(), SvenError> {
+        let effective_max = self.configured_max_mm.min(Self::MAX_HEIGHT_MM);
+        let remaining_mm = effective_max.saturating_sub(self.height_mm());
+        if remaining_mm == 0 {
+            return Err(SvenError::LimitReached(effective_max));
+        }
+        let max_ms = self.interpolated_ms_for_mm(remaining_mm);
+        if delta_ms > max_ms {
+            warn!(
+                "Capping {} ms up to {} ms at {} mm",
+                delta_ms, max_ms, effective_max
+            );
+        }
+        self.move_up(delta_ms.min(max_ms)).await
+    }
+
+    pub async fn move_down_bounded(&mut self, delta_ms: u32) -> Result<(), SvenError> {
+        let effective_min = self.configured_min_mm.max(Self::MIN_HEIGHT_MM);
+        let remaining_mm = self.height_mm().saturating_sub(effective_min);
+        if remaining_mm == 0 {
+            return Err(SvenError::LimitReached(effective_min));
+        }
+        let max_ms = self.interpolated_ms_for_mm(remaining_mm);
+        if delta_ms > max_ms {
+            warn!(
+                "Capping {} ms down to {} ms at {} mm",
+                delta_ms, max_ms, effective_min
+            );
+        }
+        self.move_down(delta_ms.min(max_ms)).await
+    }
+
+    // As move_up, but refuses to pulse past MAX_HEIGHT_MM instead of clamping
+    // the height afterwards. Returns the new height.
+    pub async fn safe_move_up(&mut self, delta_ms: u32) -> Result<u32, SvenError> {
+        let expected_mm = self
+            .height_mm()
+            .saturating_add(self.get_duration_mm(delta_ms));
+        if expected_mm > Self::MAX_HEIGHT_MM {
+            return Err(SvenError::LimitReached(Self::MAX_HEIGHT_MM));
+        }
+        self.move_up_bounded(delta_ms).await?;
+        Ok(self.height_mm())
+    }
+
+    // As move_down, but refuses to pulse past MIN_HEIGHT_MM
+    pub async fn safe_move_down(&mut self, delta_ms: u32) -> Result<u32, SvenError> {
+        let expected_mm = self
+            .height_mm()
+            .saturating_sub(self.get_duration_mm(delta_ms));
+        if expected_mm < Self::MIN_HEIGHT_MM {
+            return Err(SvenError::LimitReached(Self::MIN_HEIGHT_MM));
+        }
+        self.move_down_bounded(delta_ms).await?;
+        Ok(self.height_mm())
+    }
+
+    pub async fn move_down(&mut self, delta_ms: u32) -> Result<(), SvenError> {
+        info!("Moving down {} ms at {}", delta_ms, ntp::now_epoch_s());
+        let previous = self.begin_movement(MovementState::MovingDown {
+            started_at_ms: Instant::now().as_millis(),
+            total_ms: self.scaled_ms(delta_ms),
+        })?;
+        let pulsed_ms = self
+            .pulse_with_progress(Self::PIN_DOWN, Direction::Down, delta_ms)
+            .await;
+        self.movement_state = previous;
+        let delta_mm = self.get_duration_mm(self.unscaled_ms(pulsed_ms));
+        let height_before_mm = self.height_mm();
+        self.set_height_mm(Self::MIN_HEIGHT_MM.max(self.height_mm().saturating_sub(delta_mm)));
+        self.record_height();
+        self.position = self.get_position_from_height();
+        self.log_movement(Direction::Down, pulsed_ms, height_before_mm);
+        if pulsed_ms < self.scaled_ms(delta_ms) {
+            return Err(SvenError::MovementAborted);
+        }
+        Ok(())
+    }
+
+    // Pulses PIN_UP (0) or PIN_DOWN (1) for hardware debugging. The height is not
+    // updated, so the tracked state is unreliable afterwards. Returns the number
+    // of ms the pin was actually active.
+    pub async fn pulse_raw(&mut self, pin_idx: u8, duration_ms: u32) -> Result<u32, SvenError> {
+        let pin = match pin_idx as usize {
+            Self::PIN_UP => Self::PIN_UP,
+            Self::PIN_DOWN => Self::PIN_DOWN,
+            _ => return Err(SvenError::InvalidPin(pin_idx)),
+        };
+        warn!("Raw pulse on pin {} for {} ms", pin_idx, duration_ms);
+        Ok(self.pins[pin].pulse(duration_ms).await)
+    }
+
+    // Test pulses on both pins, (up, down). Up and down cancel out, so the height
+    // is not updated.
+    pub async fn self_test(&mut self) -> (bool, bool) {
+        info!("Self test, pulsing both pins for {} ms", Self::SELF_TEST_MS);
+        let up_ok = self.pins[Self::PIN_UP]
+            .test_toggle(Self::SELF_TEST_MS)
+            .await;
+        let down_ok = self.pins[Self::PIN_DOWN]
+            .test_toggle(Self::SELF_TEST_MS)
+            .await;
+        (up_ok, down_ok)
+    }
+
+    pub async fn nudge_up(&mut self, count: u8) -> Result<(), SvenError> {
+        self.nudge(Self::PIN_UP, count).await
+    }
+
+    pub async fn nudge_down(&mut self, count: u8) -> Result<(), SvenError> {
+        self.nudge(Self::PIN_DOWN, count).await
+    }
+
+    async fn nudge(&mut self, pin: usize, count: u8) -> Result<(), SvenError> {
+        let count = (count as usize).min(Self::MAX_NUDGES);
+        info!("Nudging {} times at {}", count, ntp::now_epoch_s());
+        let total_ms = count as u32 * (Self::NUDGE_ON_MS + Self::NUDGE_OFF_MS);
+        let started_at_ms = Instant::now().as_millis();
+        let previous = self.begin_movement(if pin == Self::PIN_UP {
+            MovementState::MovingUp {
+                started_at_ms,
+                total_ms,
+            }
+        } else {
+            MovementState::MovingDown {
+                started_at_ms,
+                total_ms,
+            }
+        })?;
+        let pulsed_ms = self.pins[pin]
+            .pulse_sequence(&Self::NUDGE_SEQUENCE[..count])
+            .await;
+        self.movement_state = previous;
+
+        // Each nudge accelerates from standstill, so convert pulse by pulse
+        let nudges = pulsed_ms / Self::NUDGE_ON_MS;
+        let delta_mm = nudges * self.get_duration_mm(Self::NUDGE_ON_MS)
+            + self.get_duration_mm(pulsed_ms % Self::NUDGE_ON_MS);
+        let height_before_mm = self.height_mm();
+        let direction = if pin == Self::PIN_UP {
+            self.set_height_mm(Self::MAX_HEIGHT_MM.min(self.height_mm().saturating_add(delta_mm)));
+            Direction::Up
+        } else {
+            self.set_height_mm(Self::MIN_HEIGHT_MM.max(self.height_mm().saturating_sub(delta_mm)));
+            Direction::Down
+        };
+        self.record_height();
+        self.position = self.get_position_from_height();
+        self.log_movement(direction, pulsed_ms, height_before_mm);
+        if pulsed_ms < count as u32 * Self::NUDGE_ON_MS {
+            return Err(SvenError::MovementAborted);
+        }
+        Ok(())
+    }
+
+    pub async fn move_up_relative(&mut self, delta_mm: u32) -> Result<(), SvenError> {
+        self.move_up_relative_with_progress(delta_mm, &|_| {}).await
+    }
+
+    async fn move_up_relative_with_progress<F: Fn(u32)>(
+        &mut self,
+        delta_mm: u32,
+        on_progress: &F,
+    ) -> Result<(), SvenError> {
+        let (max_ms, max_mm) = self.last_calibration();
+        let mut distance_left = delta_mm;
+        // Distances beyond the table are moved in chunks of the largest entry
+        while distance_left > max_mm {
+            info!("Moving up {} mm in a {} ms chunk", distance_left, max_ms);
+            self.move_up(max_ms).await?;
+            on_progress(self.height_mm());
+            embassy_time::Timer::after_millis(self.settle_ms() as u64).await;
+            distance_left -= max_mm;
+        }
+        if distance_left == 0 {
+            return Ok(());
+        }
+        let ms = self.interpolated_ms_for_mm(distance_left);
+        info!("Moving up {} mm equates to {} ms", distance_left, ms);
+        self.move_up(ms).await?;
+        on_progress(self.height_mm());
+        Ok(())
+    }
+
+    pub async fn move_down_relative(&mut self, delta_mm: u32) -> Result<(), SvenError> {
+        self.move_down_relative_with_progress(delta_mm, &|_| {})
+            .await
+    }
+
+    async fn move_down_relative_with_progress<F: Fn(u32)>(
+        &mut self,
+        delta_mm: u32,
+        on_progress: &F,
+    ) -> Result<(), SvenError> {
+        let (max_ms, max_mm) = self.last_calibration();
+        let mut distance_left = delta_mm;
+        // Distances beyond the table are moved in chunks of the largest entry
+        while distance_left > max_mm {
+            info!("Moving down {} mm in a {} ms chunk", distance_left, max_ms);
+            self.move_down(max_ms).await?;
+            on_progress(self.height_mm());
+            embassy_time::Timer::after_millis(self.settle_ms() as u64).await;
+            distance_left -= max_mm;
+        }
+        if distance_left == 0 {
+            return Ok(());
+        }
+        let ms = self.interpolated_ms_for_mm(distance_left);
+        info!("Moving down {} mm equates to {} ms", distance_left, ms);
+        self.move_down(ms).await?;
+        on_progress(self.height_mm());
+        Ok(())
+    }
+
+    // Drives the desk into its mechanical bottom stop, the only reliable way to
+    // re-sync height_mm after a power cut or a manual adjustment
+    pub async fn recalibrate_zero_at_bottom(&mut self) -> Result<(), SvenError> {
+        info!("Recalibrating from the bottom stop");
+        self.movement_state = MovementState::Homing;
+        let homed = self.move_down(HOMING_DURATION_MS).await;
+        self.movement_state = MovementState::Idle;
+        homed?;
+        self.set_height_mm(Self::MIN_HEIGHT_MM);
+        if self.position != SvenPosition::Bottom {
+            self.position = SvenPosition::Bottom;
+            self.position_changed.signal(SvenPosition::Bottom);
+        }
+        // The motor overshoots the stop for a moment, let it come to rest
+        embassy_time::Timer::after_millis(Self::HOMING_SETTLE_MS as u64).await;
+        Ok(())
+    }
+
+    // Current height mapped from MIN_HEIGHT_MM..=MAX_HEIGHT_MM to 0..=100, rounded
+    pub fn height_to_percentage(&self) -> u8 {
+        let range_mm = Self::MAX_HEIGHT_MM - Self::MIN_HEIGHT_MM;
+        let above_min_mm = self
+            .height_mm()
+            .clamp(Self::MIN_HEIGHT_MM, Self::MAX_HEIGHT_MM)
+            .saturating_sub(Self::MIN_HEIGHT_MM);
+        (above_min_mm
+            .saturating_mul(100)
+            .saturating_add(range_mm / 2)
+            / range_mm) as u8
+    }
+
+    pub async fn move_to_height_percentage(&mut self, percent: u8) -> Result<(), SvenError> {
+        if percent > 100 {
+            return Err(SvenError::InvalidPercentage(percent as u32));
+        }
+        let range_mm = Self::MAX_HEIGHT_MM - Self::MIN_HEIGHT_MM;
+        let height_mm = Self::MIN_HEIGHT_MM
+            .saturating_add((percent as u32).saturating_mul(range_mm).saturating_add(50) / 100);
+        info!("{} % equates to {} mm", percent, height_mm);
+        self.move_to_height(height_mm).await
+    }
+
+    pub async fn move_to_height(&mut self, height_mm: u32) -> Result<(), SvenError> {
+        self.move_to_height_with_progress(height_mm, |_| {}).await
+    }
+
+    // As move_to_height, but clamps to the limits set with SetLimits instead of
+    // rejecting heights outside them. Returns the height moved to.
+    pub async fn move_to_height_bounded(&mut self, height_mm: u32) -> Result<u32, SvenError> {
+        self.move_to_height_bounded_with_progress(height_mm, |_| {})
+            .await
+    }
+
+    pub async fn move_to_height_bounded_with_progress<F: Fn(u32)>(
+        &mut self,
+        height_mm: u32,
+        on_progress: F,
+    ) -> Result<u32, SvenError> {
+        let clamped_mm = self.clamp_to_limits(height_mm);
+        if clamped_mm != height_mm {
+            warn!("Clamping {} mm to {} mm", height_mm, clamped_mm);
+        }
+        self.move_to_height_with_progress(clamped_mm, on_progress)
+            .await?;
+        Ok(clamped_mm)
+    }
+
+    // As move_to_height, calling `on_progress` with the updated height_mm after
+    // each chunk of a long movement
+    pub async fn move_to_height_with_progress<F: Fn(u32)>(
+        &mut self,
+        height_mm: u32,
+        on_progress: F,
+    ) -> Result<(), SvenError> {
+        info!(
+            "Moving from height {} mm to {} mm",
+            self.height_mm(),
+            height_mm
+        );
+        if !(Self::MIN_HEIGHT_MM..=Self::MAX_HEIGHT_MM).contains(&height_mm) {
+            return Err(SvenError::InvalidHeight(height_mm));
+        }
+        let Some(direction) = direction_from_heights(self.height_mm(), height_mm) else {
+            info!("Already at height {} mm", height_mm);
+            return Ok(());
+        };
+
+        // Aborting stops the pulse in progress cleanly, unlike dropping the movement
+        let timeout_ms = self.time_to_reach_height(height_mm) + Self::MOVEMENT_TIMEOUT_MARGIN_MS;
+        let timeout = async {
+            embassy_time::Timer::after_millis(timeout_ms as u64).await;
+            warn!(
+                "Movement to {} mm timed out after {} ms",
+                height_mm, timeout_ms
+            );
+            PulsePin::abort();
+            core::future::pending::<()>().await
+        };
+        let movement = async {
+            let delta_mm = height_mm.abs_diff(self.height_mm());
+            match direction {
+                Direction::Up => {
+                    self.move_up_relative_with_progress(delta_mm, &on_progress)
+                        .await
+                }
+                Direction::Down => {
+                    self.move_down_relative_with_progress(delta_mm, &on_progress)
+                        .await
+                }
+            }
+        };
+        match select(movement, timeout).await {
+            Either::First(result) => result?,
+            Either::Second(()) => unreachable!(),
+        }
+        self.correct_height(height_mm).await
+    }
+
+    // A single correction pulse for the residual left by the interpolation,
+    // never more than MAX_CORRECTION_MM
+    async fn correct_height(&mut self, target_mm: u32) -> Result<(), SvenError> {
+        if self.is_near_height(target_mm) {
+            return Ok(());
+        }
+        let residual_mm = target_mm.abs_diff(self.height_mm());
+        // Pulses shorter than the first calibration entry are unreliable
+        let min_correction_mm = self.calibration.first().map_or(0, |&(_, mm)| mm);
+        if residual_mm < min_correction_mm {
+            info!(
+                "Skipping correction, {} mm off target {} mm",
+                residual_mm, target_mm
+            );
+            return Ok(());
+        }
+        let correction_mm = residual_mm.min(Self::MAX_CORRECTION_MM);
+        info!(
+            "Correcting {} mm towards target {} mm",
+            correction_mm, target_mm
+        );
+        match direction_from_heights(self.height_mm(), target_mm) {
+            Some(Direction::Up) => self.move_up_relative(correction_mm).await,
+            Some(Direction::Down) => self.move_down_relative(correction_mm).await,
+            None => Ok(()),
+        }
+    }
+
+    // Dispatches a command that only acts on the desk and its settings. Commands
+    // that need the firmware (scheduling, reminders, publishing, ...) are handled
+    // by async_main and ignored here. Intermediate heights of long movements are
+    // passed to `on_progress`.
+    pub async fn handle_command<F: Fn(u32)>(
+        &mut self,
+        command: &DeskCommand,
+        store: &mut ConfigStore,
+        on_progress: F,
+    ) -> Result<(), SvenError> {
+        if command.command.is_movement() && self.is_locked() {
+            return Err(SvenError::DeskLocked);
+        }
+        match command.command {
+            SvenCommand::UpDuration => {
+                info!("Moving up for {} ms", command.value);
+                self.safe_move_up(command.value).await?;
+            }
+            SvenCommand::DownDuration => {
+                info!("Moving down for {} ms", command.value);
+                self.safe_move_down(command.value).await?;
+            }
+            SvenCommand::UpRelative => {
+                info!("Moving up by {} mm", command.value);
+                self.move_up_relative(command.value).await?;
+            }
+            SvenCommand::DownRelative => {
+                info!("Moving down by {} mm", command.value);
+                self.move_down_relative(command.value).await?;
+            }
+            SvenCommand::AbsoluteHeight => {
+                info!("Setting absolute height to {} mm", command.value);
+                self.move_to_height_bounded_with_progress(command.value, &on_progress)
+                    .await?;
+            }
+            SvenCommand::AbsolutePercent => {
+                info!("Setting absolute height to {} %", command.value);
+                let percent = u8::try_from(command.value)
+                    .map_err(|_| SvenError::InvalidPercentage(command.value))?;
+                self.move_to_height_percentage(percent).await?;
+            }
+            SvenCommand::Position => {
+                info!("Setting position to {:?}", command.value);
+                let sven_position =
+                    SvenPosition::try_from(command.value).unwrap_or(SvenPosition::Armrest);
+                self.move_to_position(sven_position).await?;
+            }
+            SvenCommand::IncrementalUp => {
+                let delta_ms = self.smallest_increment_ms();
+                info!("Moving up by one increment of {} ms", delta_ms);
+                self.move_up(delta_ms).await?;
+            }
+            SvenCommand::IncrementalDown => {
+                let delta_ms = self.smallest_increment_ms();
+                info!("Moving down by one increment of {} ms", delta_ms);
+                self.move_down(delta_ms).await?;
+            }
+            SvenCommand::Toggle => {
+                let sven_position = if self.is_sitting() {
+                    SvenPosition::Standing
+                } else {
+                    SvenPosition::Armrest
+                };
+                info!("Toggling to {:?}", sven_position);
+                self.move_to_position(sven_position).await?;
+            }
+            SvenCommand::Calibrate => {
+                info!("Calibrating position at {:?}", command.value);
+                let bottom_mm = self.get_position_mm(SvenPosition::Bottom);
+                let top_mm = self.get_position_mm(SvenPosition::Top);
+
+                // The height may be outside the positions, e.g. 0 before the first homing
+                let height_mm = self.height_mm();
+                if height_mm.saturating_sub(bottom_mm) < top_mm.saturating_sub(height_mm) {
+                    self.move_to_position(SvenPosition::Bottom).await?;
+                } else {
+                    self.move_to_position(SvenPosition::Top).await?;
+                }
+
+                let sven_position =
+                    SvenPosition::try_from(command.value).unwrap_or(SvenPosition::Armrest);
+                self.move_to_position(sven_position).await?;
+            }
+            SvenCommand::EmergencyStop => {
+                warn!("Emergency stop requested");
+                PulsePin::abort();
+            }
+            SvenCommand::SavePosition => {
+                let slot = u8::try_from(command.value)
+                    .map_err(|_| SvenError::InvalidSlot(command.value))?;
+                info!("Saving height {} mm to slot {}", self.height_mm(), slot);
+                CustomPositionStore::new(store).save(slot, self.height_mm())?;
+            }
+            SvenCommand::RecallPosition => {
+                let slot = u8::try_from(command.value)
+                    .map_err(|_| SvenError::InvalidSlot(command.value))?;
+                let height_mm = CustomPositionStore::new(store)
+                    .load(slot)
+                    .ok_or(SvenError::EmptySlot(slot))?;
+                info!("Recalling slot {} at {} mm", slot, height_mm);
+                self.move_to_height_with_progress(height_mm, &on_progress)
+                    .await?;
+            }
+            SvenCommand::Recalibrate => {
+                self.recalibrate_zero_at_bottom().await?;
+                on_progress(self.height_mm());
+                if command.value != 0 {
+                    info!("Moving to {} mm after recalibration", command.value);
+                    self.move_to_height(command.value).await?;
+                }
+            }
+            SvenCommand::SetSittingHeight => {
+                self.set_position_height(SvenPosition::Armrest, command.value, store)?;
+                // The height is unchanged but the position may have been reclassified
+                self.mark_dirty();
+            }
+            SvenCommand::SetStandingHeight => {
+                self.set_position_height(SvenPosition::Standing, command.value, store)?;
+                self.mark_dirty();
+            }
+            SvenCommand::SetLimits { min_mm, max_mm } => {
+                self.set_limits(min_mm, max_mm, store)?;
+                info!("Limits set to {} - {} mm", min_mm, max_mm);
+            }
+            SvenCommand::SetPublishInterval { ms } => {
+                let interval_ms = self.set_publish_interval(ms);
+                info!("Publishing the state every {} ms", interval_ms);
+            }
+            SvenCommand::SetPositionThreshold => {
+                let threshold_mm = self.set_position_threshold(command.value);
+                info!("Position threshold set to {} mm", threshold_mm);
+            }
+            SvenCommand::SetSettleMs => {
+                let settle_ms = self.set_settle_ms(command.value);
+                info!("Pausing {} ms between movement chunks", settle_ms);
+            }
+            SvenCommand::PulseRaw {
+                pin_idx,
+                duration_ms,
+            } => {
+                self.pulse_raw(pin_idx, duration_ms).await?;
+            }
+            SvenCommand::ResetOdometer => {
+                info!("Resetting odometer at {} mm", self.total_distance_mm());
+                self.reset_odometer(store)?;
+            }
+            SvenCommand::SetSpeedFactor => {
+                let percent = self.set_speed_factor(command.value, store)?;
+                info!("Scaling pulse durations by {} %", percent);
+            }
+            SvenCommand::SwapDirections => {
+                self.swap_direction_pins();
+                info!("Direction pins swapped: {}", self.directions_swapped());
+                store.write(NvsKey::DirectionsSwapped, &self.directions_swapped())?;
+            }
+            SvenCommand::LockDesk => {
+                info!("Locking desk");
+                self.set_locked(true, store)?;
+                self.mark_dirty();
+            }
+            SvenCommand::UnlockDesk => {
+                info!("Unlocking desk");
+                self.set_locked(false, store)?;
+                self.mark_dirty();
+            }
+            SvenCommand::SetCalibration { ms, mm } => {
+                info!("Setting calibration {} ms -> {} mm", ms, mm);
+                self.set_calibration(ms, mm, store)?;
+            }
+            SvenCommand::RecordObservation { ms, actual_mm } => {
+                info!("Observed {} ms -> {} mm", ms, actual_mm);
+                self.record_movement_observation(ms, actual_mm)?;
+                let permille = self.apply_calibration_observations(store)?;
+                info!(
+                    "Scaled calibration by {} permille from {} observations",
+                    permille,
+                    self.observations.len()
+                );
+            }
+            SvenCommand::GetState => self.mark_dirty(),
+            SvenCommand::Schedule { .. }
+            | SvenCommand::CancelSchedule
+            | SvenCommand::Remind { .. }
+            | SvenCommand::MoveSequence { .. }
+            | SvenCommand::CancelSequence
+            | SvenCommand::SetReconnectDelay { .. }
+            | SvenCommand::GetDiagnostics
+            | SvenCommand::GetLog
+            | SvenCommand::GetCalibration
+            | SvenCommand::FactoryReset
+            | SvenCommand::GetFirmwareVersion
+            | SvenCommand::GetOdometer
+            | SvenCommand::Reboot
+            | SvenCommand::QueryCalibration
+            | SvenCommand::SelfTest
+            | SvenCommand::StartAutoToggle { .. }
+            | SvenCommand::StopAutoToggle
+            | SvenCommand::SetMqttClientId { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_positions_are_ordered_by_height() {
+        for (i, a) in SvenPosition::NAMED.iter().enumerate() {
+            for (j, b) in SvenPosition::NAMED.iter().enumerate() {
+                assert_eq!(a.partial_cmp(b), Some(i.cmp(&j)), "{:?} vs {:?}", a, b);
+            }
+        }
+        assert!(SvenPosition::Bottom < SvenPosition::Armrest);
+        assert!(SvenPosition::Armrest < SvenPosition::AboveArmrest);
+        assert!(SvenPosition::AboveArmrest < SvenPosition::Standing);
+        assert!(SvenPosition::Standing < SvenPosition::Top);
+    }
+
+    #[test]
+    fn custom_is_unordered_against_named_positions() {
+        for position in SvenPosition::NAMED {
+            assert_eq!(SvenPosition::Custom.partial_cmp(&position), None);
+            assert_eq!(position.partial_cmp(&SvenPosition::Custom), None);
+        }
+        assert_eq!(
+            SvenPosition::Custom.partial_cmp(&SvenPosition::Custom),
+            Some(core::cmp::Ordering::Equal)
+        );
+    }
+
+    #[test]
+    fn position_value_accepts_index_and_name() {
+        let (by_name, _) = serde_json_core::from_slice::<DeskCommand>(
+            br#"{"command":"Position","position":"sitting"}"#,
+        )
+        .unwrap();
+        let (by_index, _) =
+            serde_json_core::from_slice::<DeskCommand>(br#"{"command":"Position","value":2}"#)
+                .unwrap();
+        assert_eq!(by_name.value, SvenPosition::Armrest as u32);
+        assert_eq!(by_index.value, SvenPosition::Armrest as u32);
+    }
+
+    #[test]
+    fn position_value_rejects_ambiguous_and_unknown() {
+        assert!(serde_json_core::from_slice::<DeskCommand>(
+            br#"{"command":"Position","value":2,"position":"sitting"}"#
+        )
+        .is_err());
+        assert!(serde_json_core::from_slice::<DeskCommand>(
+            br#"{"command":"Position","position":"kneeling"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn position_names_deserialize() {
+        let (position, _) = serde_json_core::from_slice::<SvenPosition>(br#""sitting""#).unwrap();
+        assert_eq!(position, SvenPosition::Armrest);
+        let (position, _) =
+            serde_json_core::from_slice::<SvenPosition>(br#""above_armrest""#).unwrap();
+        assert_eq!(position, SvenPosition::AboveArmrest);
+        let (state, _) = serde_json_core::from_slice::<SvenStateMsg>(
+            br#"{"height_mm":1140,"position":"Standing"}"#,
+        )
+        .unwrap();
+        assert_eq!(state.position, SvenPosition::Standing);
+    }
+
+    #[test]
+    fn batch_mixes_indices_and_names() {
+        let (batch, _) = serde_json_core::from_slice::<BatchCommands>(
+            br#"[{"command":"AbsoluteHeight","value":1140},{"delay_ms":1000},{"command":"Position","position":"sitting"}]"#,
+        )
+        .unwrap();
+        assert_eq!(batch.len(), 3);
+        match &batch[2] {
+            BatchCommand::Command(command) => {
+                assert_eq!(command.value, SvenPosition::Armrest as u32)
+            }
+            BatchCommand::Delay { .. } => panic!("expected a command"),
+        }
+    }
+
+    #[test]
+    fn swapped_pins_drive_the_other_direction() {
+        let mut pins = DirectionPins::new("up", "down");
+        pins.swap();
+        assert!(pins.swapped());
+        assert_eq!(pins[SvenState::PIN_UP], "down");
+        assert_eq!(pins[SvenState::PIN_DOWN], "up");
+    }
+
+    #[test]
+    fn swapping_twice_restores_the_pins() {
+        let mut pins = DirectionPins::new("up", "down");
+        pins.swap();
+        pins.swap();
+        assert!(!pins.swapped());
+        assert_eq!(pins[SvenState::PIN_UP], "up");
+        assert_eq!(pins[SvenState::PIN_DOWN], "down");
+    }
+
+    #[test]
+    fn restored_swap_is_applied_once() {
+        let mut pins = DirectionPins::new("up", "down");
+        pins.set_swapped(true);
+        pins.set_swapped(true);
+        assert!(pins.swapped());
+        assert_eq!(pins[SvenState::PIN_UP], "down");
+        // The flag saved by SwapDirections round-trips through postcard
+        let mut buf = [0u8; 1];
+        let saved = postcard::to_slice(&pins.swapped(), &mut buf).unwrap();
+        assert!(postcard::from_bytes::<bool>(saved).unwrap());
+    }
+
+    #[test]
+    fn all_variants_round_trip_through_names() {
+        for position in SvenPosition::all_variants() {
+            assert_eq!(SvenPosition::try_from(position.as_str()), Ok(position));
+            assert_eq!(SvenPosition::from_name(position.as_str()), Some(position));
+        }
+    }
+
+    #[test]
+    fn all_variants_round_trip_through_json() {
+        for position in SvenPosition::all_variants() {
+            let json: serde_json_core::heapless::String<32> =
+                serde_json_core::to_string(&position).unwrap();
+            let (parsed, _) = serde_json_core::from_str::<SvenPosition>(&json).unwrap();
+            assert_eq!(parsed, position, "{}", json);
+        }
+    }
+
+    #[test]
+    fn all_variants_is_exhaustive() {
+        let variants = SvenPosition::all_variants();
+        for (i, position) in variants.iter().enumerate() {
+            // Fails to compile when a variant is added, extend all_variants too
+            match position {
+                SvenPosition::Bottom
+                | SvenPosition::Top
+                | SvenPosition::Armrest
+                | SvenPosition::AboveArmrest
+                | SvenPosition::Standing
+                | SvenPosition::Custom => {}
+            }
+            assert!(
+                !variants[..i].contains(position),
+                "{:?} listed twice",
+                position
+            );
+        }
+        for index in 0..variants.len() as u32 {
+            assert!(variants.contains(&SvenPosition::try_from(index).unwrap()));
+        }
+        assert!(SvenPosition::try_from(variants.len() as u32).is_err());
+    }
+
+    #[test]
+    fn known_height_accepts_the_full_range() {
+        let positions = SvenState::default_positions();
+        assert_eq!(
+            known_position(
+                &positions,
+                SvenState::MIN_HEIGHT_MM,
+                SvenPosition::Custom,
+                5
+            )
+            .ok(),
+            Some(SvenPosition::Bottom)
+        );
+        assert_eq!(
+            known_position(
+                &positions,
+                SvenState::MAX_HEIGHT_MM,
+                SvenPosition::Custom,
+                5
+            )
+            .ok(),
+            Some(SvenPosition::Top)
+        );
+        assert_eq!(
+            known_position(&positions, 950, SvenPosition::Custom, 5).ok(),
+            Some(SvenPosition::Custom)
+        );
+    }
+
+    #[test]
+    fn known_height_rejects_out_of_range() {
+        let positions = SvenState::default_positions();
+        for height_mm in [
+            0,
+            SvenState::MIN_HEIGHT_MM - 1,
+            SvenState::MAX_HEIGHT_MM + 1,
+        ] {
+            assert!(matches!(
+                known_position(&positions, height_mm, SvenPosition::Custom, 5),
+                Err(SvenError::InvalidHeight(mm)) if mm == height_mm
+            ));
+        }
+    }
+
+    #[test]
+    fn known_height_reclassifies_custom() {
+        let positions = SvenState::default_positions();
+        let standing_mm = SvenPosition::Standing.canonical_height().unwrap();
+        assert_eq!(
+            known_position(&positions, standing_mm + 3, SvenPosition::Custom, 5).ok(),
+            Some(SvenPosition::Standing)
+        );
+        assert_eq!(
+            known_position(&positions, standing_mm + 6, SvenPosition::Custom, 5).ok(),
+            Some(SvenPosition::Custom)
+        );
+        // An explicit named position is kept
+        assert_eq!(
+            known_position(&positions, standing_mm + 6, SvenPosition::Standing, 5).ok(),
+            Some(SvenPosition::Standing)
+        );
+    }
+
+    #[test]
+    fn interpolates_between_calibration_entries() {
+        let calibration = SvenState::MS_TO_CM;
+        assert_eq!(interpolated_mm(calibration, 1500), 28);
+        assert_eq!(interpolated_mm(calibration, 2500), 65);
+        assert_eq!(interpolated_mm(calibration, 9500), 328);
+    }
+
+    #[test]
+    fn interpolates_exact_calibration_entries() {
+        for &(ms, mm) in SvenState::MS_TO_CM {
+            assert_eq!(interpolated_mm(SvenState::MS_TO_CM, ms), mm);
+        }
+    }
+
+    #[test]
+    fn interpolates_below_the_first_entry_from_zero() {
+        let calibration = SvenState::MS_TO_CM;
+        assert_eq!(interpolated_mm(calibration, 0), 0);
+        assert_eq!(interpolated_mm(calibration, 500), 4);
+    }
+
+    #[test]
+    fn extrapolates_beyond_the_last_entry() {
+        let calibration = SvenState::MS_TO_CM;
+        assert_eq!(interpolated_mm(calibration, 11_000), 347 + 38);
+        assert_eq!(interpolated_mm(calibration, 12_500), 347 + 95);
+    }
+
+    #[test]
+    fn default_calibration_is_valid() {
+        assert!(is_valid_calibration(SvenState::MS_TO_CM));
+    }
+
+    #[test]
+    fn invalid_calibration_is_rejected() {
+        assert!(!is_valid_calibration(&[]));
+        // Zero durations or distances leave nothing to interpolate from (0, 0)
+        assert!(!is_valid_calibration(&[(0, 9), (2000, 48)]));
+        assert!(!is_valid_calibration(&[(1000, 0), (2000, 48)]));
+        // Unsorted durations
+        assert!(!is_valid_calibration(&[(2000, 9), (1000, 48)]));
+        assert!(!is_valid_calibration(&[(1000, 9), (1000, 48)]));
+        // Decreasing or repeated distances
+        assert!(!is_valid_calibration(&[(1000, 48), (2000, 9)]));
+        assert!(!is_valid_calibration(&[(1000, 9), (2000, 9)]));
+    }
+
+    // Records the pulses instead of driving a GPIO
+    struct RecordingPin {
+        name: &'static str,
+        pulses: heapless::Vec<u32, 8>,
+    }
+
+    impl RecordingPin {
+        fn new(name: &'static str) -> Self {
+            RecordingPin {
+                name,
+                pulses: heapless::Vec::new(),
+            }
+        }
+    }
+
+    impl Pulse for RecordingPin {
+        async fn wait_for_cooldown(&self) {}
+
+        async fn pulse(&mut self, duration: u32) -> u32 {
+            self.pulses.push(duration).unwrap();
+            duration
+        }
+
+        async fn pulse_sequence(&mut self, sequence: &[(u32, u32)]) -> u32 {
+            let active_ms = sequence.iter().map(|&(on_ms, _)| on_ms).sum();
+            self.pulses.push(active_ms).unwrap();
+            active_ms
+        }
+
+        async fn test_toggle(&mut self, duration_ms: u32) -> bool {
+            self.pulse(duration_ms).await;
+            true
+        }
+    }
+
+    fn recording_desk(height_mm: u32) -> SvenDesk<RecordingPin> {
+        SvenDesk::new_uncalibrated(
+            RecordingPin::new("up"),
+            RecordingPin::new("down"),
+            height_mm,
+            SvenPosition::Custom,
+        )
+    }
+
+    fn dispatch(
+        desk: &mut SvenDesk<RecordingPin>,
+        command: SvenCommand,
+        value: u32,
+    ) -> Result<(), SvenError> {
+        let command = DeskCommand {
+            command,
+            value,
+            correlation_id: None,
+        };
+        embassy_futures::block_on(desk.handle_command(&command, &mut ConfigStore::new(), |_| {}))
+    }
+
+    // Pulses of the pin wired as `name`, wherever a swap moved it
+    fn pulses<'a>(desk: &'a SvenDesk<RecordingPin>, name: &str) -> &'a [u32] {
+        let pin = [SvenState::PIN_UP, SvenState::PIN_DOWN]
+            .into_iter()
+            .map(|pin| &desk.pins[pin])
+            .find(|pin| pin.name == name)
+            .unwrap();
+        &pin.pulses
+    }
+
+    #[test]
+    fn up_duration_drives_the_up_pin() {
+        let mut desk = recording_desk(800);
+        dispatch(&mut desk, SvenCommand::UpDuration, 1000).unwrap();
+        assert_eq!(pulses(&desk, "up"), &[1000]);
+        assert!(pulses(&desk, "down").is_empty());
+        assert_eq!(desk.height_mm(), 809);
+    }
+
+    #[test]
+    fn down_duration_drives_the_down_pin() {
+        let mut desk = recording_desk(800);
+        dispatch(&mut desk, SvenCommand::DownDuration, 2000).unwrap();
+        assert!(pulses(&desk, "up").is_empty());
+        assert_eq!(pulses(&desk, "down"), &[2000]);
+        assert_eq!(desk.height_mm(), 752);
+    }
+
+    #[test]
+    fn swapped_desk_drives_the_other_pin() {
+        let mut desk = recording_desk(800);
+        desk.swap_direction_pins();
+        dispatch(&mut desk, SvenCommand::UpDuration, 1000).unwrap();
+        dispatch(&mut desk, SvenCommand::DownDuration, 2000).unwrap();
+        assert_eq!(pulses(&desk, "up"), &[2000]);
+        assert_eq!(pulses(&desk, "down"), &[1000]);
+    }
+
+    #[test]
+    fn raw_pulse_drives_the_requested_pin() {
+        let mut desk = recording_desk(800);
+        let command = SvenCommand::PulseRaw {
+            pin_idx: 1,
+            duration_ms: 500,
+        };
+        dispatch(&mut desk, command, 0).unwrap();
+        assert!(pulses(&desk, "up").is_empty());
+        assert_eq!(pulses(&desk, "down"), &[500]);
+        // The height is not tracked for raw pulses
+        assert_eq!(desk.height_mm(), 800);
+
+        let command = SvenCommand::PulseRaw {
+            pin_idx: 2,
+            duration_ms: 500,
+        };
+        assert!(matches!(
+            dispatch(&mut desk, command, 0),
+            Err(SvenError::InvalidPin(2))
+        ));
+    }
+
+    #[test]
+    fn locked_desk_drives_no_pin() {
+        let mut desk = recording_desk(800);
+        desk.locked.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            dispatch(&mut desk, SvenCommand::UpDuration, 1000),
+            Err(SvenError::DeskLocked)
+        ));
+        assert!(pulses(&desk, "up").is_empty());
+        assert!(pulses(&desk, "down").is_empty());
+    }
+
+    #[test]
+    fn all_named_positions_are_sorted_by_height() {
+        let mut desk = recording_desk(800);
+        let positions: heapless::Vec<(SvenPosition, u32), 8> = desk.all_named_positions().collect();
+        assert_eq!(positions.len(), 5);
+        assert!(positions.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert!(positions
+            .iter()
+            .map(|&(position, _)| position)
+            .eq(SvenPosition::NAMED));
+
+        // Sorted by the current heights, not by the declaration order
+        desk.set_position_mm(SvenPosition::Armrest, 1200);
+        assert!(desk
+            .all_named_positions()
+            .map(|(position, _)| position)
+            .eq([
+                SvenPosition::Bottom,
+                SvenPosition::AboveArmrest,
+                SvenPosition::Standing,
+                SvenPosition::Armrest,
+                SvenPosition::Top,
+            ]));
+    }
 }